@@ -30,6 +30,8 @@ CREATE TABLE public.documents (
     blocked boolean NOT NULL,
     provider character varying(256) NOT NULL,
     categories character varying(65536),
+    provider_results character varying(65536),
+    image_info character varying(65536),
     doc_hash character varying(256) NOT NULL,
     updated_at timestamp with time zone NOT NULL
 );
@@ -37,6 +39,39 @@ CREATE TABLE public.documents (
 
 ALTER TABLE public.documents OWNER TO imgproxy;
 
+--
+-- Name: hash_list_audit; Type: TABLE; Schema: public; Owner: imgproxy
+--
+
+CREATE TABLE public.hash_list_audit (
+    id character varying(512) NOT NULL,
+    url character varying(65536) NOT NULL,
+    url_hash character varying(256) NOT NULL,
+    doc_hash character varying(256) NOT NULL,
+    match_type character varying(256) NOT NULL,
+    entry character varying(256) NOT NULL,
+    reference character varying(65536),
+    distance integer NOT NULL,
+    created_at timestamp with time zone NOT NULL
+);
+
+
+ALTER TABLE public.hash_list_audit OWNER TO imgproxy;
+
+--
+-- Name: image_hashes; Type: TABLE; Schema: public; Owner: imgproxy
+--
+
+CREATE TABLE public.image_hashes (
+    hash bigint NOT NULL,
+    action character varying(256) NOT NULL,
+    categories character varying(65536),
+    updated_at timestamp with time zone NOT NULL
+);
+
+
+ALTER TABLE public.image_hashes OWNER TO imgproxy;
+
 --
 -- Name: report; Type: TABLE; Schema: public; Owner: imgproxy
 --
@@ -60,6 +95,14 @@ ALTER TABLE ONLY public.documents
     ADD CONSTRAINT documents_pkey PRIMARY KEY (url_hash);
 
 
+--
+-- Name: image_hashes image_hashes_pkey; Type: CONSTRAINT; Schema: public; Owner: imgproxy
+--
+
+ALTER TABLE ONLY public.image_hashes
+    ADD CONSTRAINT image_hashes_pkey PRIMARY KEY (hash);
+
+
 --
 -- Name: report report_pkey; Type: CONSTRAINT; Schema: public; Owner: imgproxy
 --
@@ -75,6 +118,13 @@ ALTER TABLE ONLY public.report
 CREATE INDEX doc_hash_idx ON public.documents USING btree (doc_hash);
 
 
+--
+-- Name: hash_list_audit_url_hash_idx; Type: INDEX; Schema: public; Owner: imgproxy
+--
+
+CREATE INDEX hash_list_audit_url_hash_idx ON public.hash_list_audit USING btree (url_hash);
+
+
 --
 -- Name: report_url_hash_idx; Type: INDEX; Schema: public; Owner: imgproxy
 --
@@ -0,0 +1,10 @@
+--
+-- Upgrades a database created from an earlier imgproxy.sql. New databases
+-- already have these columns, running this again is harmless.
+--
+
+-- Per provider verdicts of the ensemble moderation provider
+ALTER TABLE public.documents ADD COLUMN IF NOT EXISTS provider_results character varying(65536);
+
+-- Image properties returned with fetch and describe results
+ALTER TABLE public.documents ADD COLUMN IF NOT EXISTS image_info character varying(65536);
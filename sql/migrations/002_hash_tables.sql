@@ -0,0 +1,26 @@
+--
+-- Adds the image hash list and hash list audit tables to a database created
+-- from an earlier imgproxy.sql. Running this again is harmless.
+--
+
+CREATE TABLE IF NOT EXISTS public.image_hashes (
+    hash bigint NOT NULL,
+    action character varying(256) NOT NULL,
+    categories character varying(65536),
+    updated_at timestamp with time zone NOT NULL,
+    CONSTRAINT image_hashes_pkey PRIMARY KEY (hash)
+);
+
+CREATE TABLE IF NOT EXISTS public.hash_list_audit (
+    id character varying(512) NOT NULL,
+    url character varying(65536) NOT NULL,
+    url_hash character varying(256) NOT NULL,
+    doc_hash character varying(256) NOT NULL,
+    match_type character varying(256) NOT NULL,
+    entry character varying(256) NOT NULL,
+    reference character varying(65536),
+    distance integer NOT NULL,
+    created_at timestamp with time zone NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS hash_list_audit_url_hash_idx ON public.hash_list_audit USING btree (url_hash);
@@ -1,9 +1,10 @@
 use async_trait::async_trait;
 use aws_config::Region;
-use log::{debug, error, warn};
-use std::env;
+use aws_credential_types::provider::ProvideCredentials;
+use log::{debug, error, info, warn};
 
 use crate::{
+    config::AwsConfig,
     document::Document,
     moderation::{
         ModerationCategories, ModerationProvider, ModerationResponse, ModerationService,
@@ -16,7 +17,7 @@ use aws_sdk_rekognition::{error::SdkError, operation::detect_moderation_labels::
 use aws_sdk_rekognition:: Client as ClientRekognition;
 
 pub struct Rekognition {
-    pub region: String,
+    client: ClientRekognition,
 }
 
 #[async_trait]
@@ -52,11 +53,12 @@ impl ModerationProvider for Rekognition {
                 Ok(ModerationResponse {
                     categories: labels,
                     provider: ModerationService::Aws,
+                    provider_results: vec![],
                 })
             }
             Err(e) => {
                 error!("Moderation failed, id={}, reason:{}", document.id, e);
-                Err(Errors::ModerationFailed)
+                Err(Rekognition::map_error(&e))
             }
         }
     }
@@ -77,15 +79,33 @@ impl Rekognition {
         &self,
         bytes: &hyper::body::Bytes,
     ) -> Result<DetectModerationLabelsOutput, SdkError<DetectModerationLabelsError, >> {
-        let region = Region::new(self.region.clone());
-        let shared_config = aws_config::from_env().region(region).load().await;
-        let client = ClientRekognition::new(&shared_config);
-        let req = client.detect_moderation_labels();
+        let req = self.client.detect_moderation_labels();
         let blob = Blob::new(bytes.as_ref());
         let img = Image::builder().bytes(blob).build();
         req.image(img).send().await
     }
 
+    /// Tells problems with the document apart from Rekognition being
+    /// unavailable, only the latter are worth trying another provider for
+    fn map_error(e: &SdkError<DetectModerationLabelsError>) -> Errors {
+        match e {
+            SdkError::ServiceError(service_error) => match service_error.err() {
+                DetectModerationLabelsError::ImageTooLargeException(_) => Errors::DocumentTooLarge,
+                DetectModerationLabelsError::InvalidImageFormatException(_)
+                | DetectModerationLabelsError::InvalidParameterException(_) => {
+                    Errors::UnsupportedImageType
+                }
+                DetectModerationLabelsError::ThrottlingException(_)
+                | DetectModerationLabelsError::ProvisionedThroughputExceededException(_)
+                | DetectModerationLabelsError::InternalServerError(_) => Errors::ModerationFailed,
+                _ if service_error.raw().status().is_server_error() => Errors::ModerationFailed,
+                _ => Errors::ModerationRejected,
+            },
+            SdkError::ConstructionFailure(_) => Errors::ModerationRejected,
+            _ => Errors::ModerationFailed,
+        }
+    }
+
     pub fn normalize_category(input: &str) -> ModerationCategories {
         match input {
             "Explicit Nudity" => ModerationCategories::ExplicitNudity,
@@ -109,11 +129,35 @@ impl Rekognition {
         }
     }
 
-    pub fn new(aws_region: &str) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
-        env::var("AWS_ACCESS_KEY_ID").expect("AWS_ACCESS_KEY_ID key not set");
-        env::var("AWS_SECRET_ACCESS_KEY").expect("AWS_SECRET_ACCESS_KEY key not set");
+    /// Builds a long lived Rekognition client. Credentials are resolved
+    /// through the standard AWS provider chain (environment, profile files,
+    /// web identity, container and instance metadata) and checked once here
+    /// so that misconfiguration is reported at start up.
+    pub async fn new(config: &AwsConfig) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let loader = aws_config::from_env().region(Region::new(config.region.clone()));
+        let loader = if let Some(endpoint_url) = &config.endpoint_url {
+            info!("Using custom Rekognition endpoint `{}`", endpoint_url);
+            loader.endpoint_url(endpoint_url)
+        } else {
+            loader
+        };
+        let shared_config = loader.load().await;
+
+        match shared_config.credentials_provider() {
+            Some(provider) => {
+                provider.provide_credentials().await.map_err(|e| {
+                    format!(
+                        "Unable to resolve AWS credentials for Rekognition, reason={}",
+                        e
+                    )
+                })?;
+            }
+            None => return Err("No AWS credentials provider available for Rekognition".into()),
+        }
+
+        info!("Rekognition client initialized, region={}", config.region);
         Ok(Rekognition {
-            region: aws_region.to_string(),
+            client: ClientRekognition::new(&shared_config),
         })
     }
 }
@@ -0,0 +1,118 @@
+use std::f32::consts::PI;
+
+use image::RgbaImage;
+
+const BASE83_CHARACTERS: &[u8; 83] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+fn encode_base83(value: u32, length: u32, hash: &mut String) {
+    for i in 1..=length {
+        let digit = (value / 83_u32.pow(length - i)) % 83;
+        hash.push(BASE83_CHARACTERS[digit as usize] as char);
+    }
+}
+
+fn srgb_to_linear(value: u8) -> f32 {
+    let value = value as f32 / 255_f32;
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f32) -> u32 {
+    let value = value.clamp(0_f32, 1_f32);
+    if value <= 0.003_130_8 {
+        (value * 12.92 * 255_f32 + 0.5) as u32
+    } else {
+        ((1.055 * value.powf(1_f32 / 2.4) - 0.055) * 255_f32 + 0.5) as u32
+    }
+}
+
+fn sign_pow(value: f32, exponent: f32) -> f32 {
+    value.abs().powf(exponent).copysign(value)
+}
+
+/// Weight of each pixel's color for one cosine component
+fn component(image: &RgbaImage, i: u32, j: u32) -> [f32; 3] {
+    let (width, height) = image.dimensions();
+    let normalisation = if i == 0 && j == 0 { 1_f32 } else { 2_f32 };
+    let mut factor = [0_f32; 3];
+    for (x, y, pixel) in image.enumerate_pixels() {
+        let basis = normalisation
+            * (PI * i as f32 * x as f32 / width as f32).cos()
+            * (PI * j as f32 * y as f32 / height as f32).cos();
+        for (f, channel) in factor.iter_mut().zip(pixel.0.iter()) {
+            *f += basis * srgb_to_linear(*channel);
+        }
+    }
+    let scale = 1_f32 / (width * height) as f32;
+    factor.map(|f| f * scale)
+}
+
+/// Computes the BlurHash (https://blurha.sh) of the image, a short string
+/// from which clients can render a blurred placeholder. Components must be
+/// between 1 and 9 in each direction. Alpha is ignored.
+pub fn encode(image: &RgbaImage, components_x: u32, components_y: u32) -> String {
+    let mut factors = Vec::new();
+    for j in 0..components_y {
+        for i in 0..components_x {
+            factors.push(component(image, i, j));
+        }
+    }
+    let (dc, ac) = factors.split_first().unwrap_or((&[0_f32; 3], &[]));
+
+    let mut hash = String::new();
+    encode_base83((components_x - 1) + (components_y - 1) * 9, 1, &mut hash);
+
+    let max_value = if ac.is_empty() {
+        encode_base83(0, 1, &mut hash);
+        1_f32
+    } else {
+        let actual_max = ac.iter().flatten().fold(0_f32, |m, v| m.max(v.abs()));
+        let quantised = (actual_max * 166_f32 - 0.5).floor().clamp(0_f32, 82_f32) as u32;
+        encode_base83(quantised, 1, &mut hash);
+        (quantised + 1) as f32 / 166_f32
+    };
+
+    let dc_value =
+        (linear_to_srgb(dc[0]) << 16) + (linear_to_srgb(dc[1]) << 8) + linear_to_srgb(dc[2]);
+    encode_base83(dc_value, 4, &mut hash);
+
+    for factor in ac {
+        let quantised = factor.map(|v| {
+            (sign_pow(v / max_value, 0.5) * 9_f32 + 9.5)
+                .floor()
+                .clamp(0_f32, 18_f32) as u32
+        });
+        encode_base83(
+            quantised[0] * 19 * 19 + quantised[1] * 19 + quantised[2],
+            2,
+            &mut hash,
+        );
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let image = RgbaImage::from_pixel(16, 8, Rgba([255, 255, 255, 255]));
+        assert_eq!(encode(&image, 1, 1), "00TSUA");
+
+        let image = RgbaImage::from_fn(32, 32, |x, _| Rgba([(x * 8) as u8, 0, 0, 255]));
+        let hash = encode(&image, 4, 3);
+        assert_eq!(hash.len(), 28);
+        assert!(hash.starts_with('L'));
+        assert_ne!(
+            hash,
+            encode(&RgbaImage::from_pixel(32, 32, Rgba([0, 0, 0, 255])), 4, 3)
+        );
+    }
+}
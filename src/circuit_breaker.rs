@@ -0,0 +1,160 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    Closed,
+    HalfOpen,
+    Open,
+}
+
+impl BreakerState {
+    /// Numeric representation used for gauges
+    pub fn as_i64(&self) -> i64 {
+        match self {
+            BreakerState::Closed => 0,
+            BreakerState::HalfOpen => 1,
+            BreakerState::Open => 2,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct CircuitBreakerConfig {
+    /// Consecutive failures after which the breaker opens
+    pub failure_threshold: u32,
+    /// Time in seconds the breaker stays open before a trial call is let through
+    pub cooldown: u64,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            failure_threshold: 5,
+            cooldown: 30,
+        }
+    }
+}
+
+struct BreakerInner {
+    state: BreakerState,
+    consecutive_failures: u32,
+    opened_at: Instant,
+}
+
+/// A simple consecutive failure circuit breaker.
+///
+/// The breaker opens after `failure_threshold` consecutive failures. Once
+/// `cooldown` has elapsed a single trial call is allowed through; its outcome
+/// either closes the breaker or opens it for another cooldown period.
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+    inner: Mutex<BreakerInner>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: &CircuitBreakerConfig) -> Self {
+        CircuitBreaker {
+            failure_threshold: config.failure_threshold.max(1),
+            cooldown: Duration::from_secs(config.cooldown),
+            inner: Mutex::new(BreakerInner {
+                state: BreakerState::Closed,
+                consecutive_failures: 0,
+                opened_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Returns true if a call should be attempted
+    pub fn allow(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state {
+            BreakerState::Closed => true,
+            BreakerState::Open | BreakerState::HalfOpen
+                if inner.opened_at.elapsed() >= self.cooldown =>
+            {
+                // Let one trial through. Restarting the clock means a trial
+                // that never reports back only blocks for one more cooldown.
+                inner.state = BreakerState::HalfOpen;
+                inner.opened_at = Instant::now();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.state = BreakerState::Closed;
+        inner.consecutive_failures = 0;
+    }
+
+    pub fn record_failure(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.consecutive_failures = inner.consecutive_failures.saturating_add(1);
+        if inner.state == BreakerState::HalfOpen
+            || inner.consecutive_failures >= self.failure_threshold
+        {
+            inner.state = BreakerState::Open;
+            inner.opened_at = Instant::now();
+        }
+    }
+
+    pub fn state(&self) -> BreakerState {
+        self.inner.lock().unwrap().state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_breaker_opens_after_threshold() {
+        let breaker = CircuitBreaker::new(&CircuitBreakerConfig {
+            failure_threshold: 3,
+            cooldown: 60,
+        });
+        assert!(breaker.allow());
+        breaker.record_failure();
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(breaker.allow());
+
+        // A success resets the failure count
+        breaker.record_success();
+        breaker.record_failure();
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn test_breaker_half_open_trial() {
+        let breaker = CircuitBreaker::new(&CircuitBreakerConfig {
+            failure_threshold: 1,
+            cooldown: 0,
+        });
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        // Cooldown has elapsed, a trial is allowed
+        assert!(breaker.allow());
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+
+        // Failed trial re-opens the breaker
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        // Successful trial closes it
+        assert!(breaker.allow());
+        breaker.record_success();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+}
@@ -3,7 +3,65 @@ use std::net::Ipv4Addr;
 use hocon::{Error, HoconLoader};
 use serde::Deserialize;
 
-use crate::{cache::CacheConfig, moderation::ModerationService};
+use crate::{
+    cache::CacheConfig,
+    circuit_breaker::CircuitBreakerConfig,
+    dns::DnsConfig,
+    document::{ImageLimits, ResizeConfig},
+    hashlist::HashListConfig,
+    http::{
+        filters::{destination::DestinationFilterConfig, domain::DomainFilterConfig},
+        origin::OriginConfig,
+        retry::RetryConfig,
+    },
+    image_pool::ImagePoolConfig,
+    moderation::{ensemble::VotingStrategy, ModerationService},
+};
+
+/// How sampled frames of animated images are submitted for moderation
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub enum AnimationMode {
+    /// Each sampled frame is moderated separately
+    PerFrame,
+    /// Sampled frames are tiled into a single image, costing one request
+    ContactSheet,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct SvgConfig {
+    /// Serve a PNG rendering of SVG documents instead of the sanitized SVG
+    pub serve_raster: bool,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct MetadataConfig {
+    /// Remove EXIF, XMP and textual metadata from served JPEG, PNG and WebP
+    /// images, keeping ICC color profiles and the orientation
+    pub strip: bool,
+}
+
+/// What is returned in place of a blocked image
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub enum PlaceholderMode {
+    /// A heavily blurred rendition of the image
+    Blur,
+    /// A coarsely pixelated rendition of the image
+    Pixelate,
+    /// A BlurHash string in the JSON result, no image is returned
+    BlurHash,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct PlaceholderConfig {
+    pub mode: PlaceholderMode,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct AnimationConfig {
+    /// Maximum number of frames sampled from an animated GIF or APNG
+    pub max_frames: u32,
+    pub mode: AnimationMode,
+}
 
 #[derive(Deserialize, Clone)]
 #[allow(dead_code)]
@@ -37,12 +95,37 @@ pub struct DatabaseConfig {
 #[derive(Deserialize, Clone)]
 pub struct AwsConfig {
     pub region: String,
+    /// Optional endpoint override, e.g. for a local mock of the service
+    pub endpoint_url: Option<String>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct EnsembleMemberConfig {
+    pub provider: ModerationService,
+    pub weight: Option<f64>,
+    pub aws: Option<AwsConfig>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct EnsembleConfig {
+    pub strategy: VotingStrategy,
+    pub threshold: Option<f64>,
+    pub members: Vec<EnsembleMemberConfig>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct FallbackModerationConfig {
+    pub provider: ModerationService,
+    pub aws: Option<AwsConfig>,
 }
 
 #[derive(Deserialize, Clone)]
 pub struct ModerationConfig {
     pub provider: ModerationService,
     pub aws: Option<AwsConfig>,
+    pub ensemble: Option<EnsembleConfig>,
+    pub fallback: Option<FallbackModerationConfig>,
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
     pub labels: Vec<String>, //TODO
 }
 
@@ -63,6 +146,15 @@ pub struct IpfsGatewayConfig {
     pub fallback: Option<Host>,
 }
 
+#[derive(Deserialize, Clone)]
+pub struct PerceptualHashConfig {
+    /// Maximum hamming distance at which two image hashes are considered a match
+    pub max_distance: u32,
+    /// Maximum hamming distance at which `Allow` entries match, only exact
+    /// matches if omitted. Never more than `max_distance`.
+    pub max_allow_distance: Option<u32>,
+}
+
 #[derive(Deserialize, Clone)]
 #[allow(dead_code)]
 pub struct Configuration {
@@ -74,11 +166,26 @@ pub struct Configuration {
     pub timeout: u64,
     pub metrics_enabled: bool,
     pub max_document_size: Option<u64>,
+    pub max_redirects: Option<u32>,
+    pub origins: Option<OriginConfig>,
+    pub retries: Option<RetryConfig>,
+    pub dns: Option<DnsConfig>,
+    pub destination_filter: Option<DestinationFilterConfig>,
+    pub domain_filter: Option<DomainFilterConfig>,
     pub client_useragent: Option<String>,
     pub security: SecurityConfig,
     pub database: DatabaseConfig,
     pub moderation: ModerationConfig,
     pub cache_config: CacheConfig,
+    pub perceptual_hash: Option<PerceptualHashConfig>,
+    pub hash_list: Option<HashListConfig>,
+    pub animation: Option<AnimationConfig>,
+    pub svg: Option<SvgConfig>,
+    pub placeholder: Option<PlaceholderConfig>,
+    pub metadata: Option<MetadataConfig>,
+    pub resize: Option<ResizeConfig>,
+    pub image_pool: Option<ImagePoolConfig>,
+    pub image_limits: Option<ImageLimits>,
 }
 
 impl Configuration {
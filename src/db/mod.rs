@@ -1,11 +1,14 @@
 use self::postgres::PostgresDatabase;
 use crate::{
     config::DatabaseConfig,
-    moderation::{ModerationCategories, ModerationService},
+    document::ImageInfo,
+    hashlist::HashListMatch,
+    moderation::{ModerationCategories, ModerationService, ProviderResult},
 };
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
 use log::info;
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 mod postgres;
@@ -18,7 +21,10 @@ pub struct DbModerationRow {
     pub blocked: bool,
     pub categories: Vec<ModerationCategories>,
     pub provider: ModerationService,
+    pub provider_results: Vec<ProviderResult>,
     pub url: String,
+    /// Image properties recorded when the document was moderated
+    pub image_info: Option<ImageInfo>,
 }
 
 #[derive(Clone)]
@@ -29,6 +35,22 @@ pub struct DbReportRow {
     pub updated_at: DateTime<Utc>,
 }
 
+/// Action to take when a document matches an entry of the image hash list
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ImageHashAction {
+    Allow,
+    Block,
+}
+
+#[derive(Clone)]
+pub struct DbImageHashRow {
+    pub hash: u64,
+    pub action: ImageHashAction,
+    pub categories: Vec<ModerationCategories>,
+    /// Hamming distance between the stored hash and the queried one
+    pub distance: u32,
+}
+
 #[async_trait]
 pub trait DatabaseProvider {
     async fn add_report(
@@ -46,6 +68,8 @@ pub trait DatabaseProvider {
         provider: ModerationService,
         blocked: bool,
         categories: &[ModerationCategories],
+        provider_results: &[ProviderResult],
+        image_info: Option<&ImageInfo>,
     ) -> Result<()>;
 
     async fn add_moderation_result(
@@ -54,9 +78,30 @@ pub trait DatabaseProvider {
         provider: ModerationService,
         blocked: bool,
         categories: &[ModerationCategories],
+        provider_results: &[ProviderResult],
+        image_info: Option<&ImageInfo>,
     ) -> Result<()>;
 
     async fn get_moderation_result(&self, url: &[String]) -> Result<Vec<DbModerationRow>>;
+
+    async fn add_image_hash(
+        &self,
+        hash: u64,
+        action: ImageHashAction,
+        categories: &[ModerationCategories],
+    ) -> Result<()>;
+
+    /// Records a document matching the industry hash list
+    async fn add_hash_list_audit(
+        &self,
+        id: &Uuid,
+        url: &str,
+        doc_hash: &str,
+        hash_list_match: &HashListMatch,
+    ) -> Result<()>;
+
+    /// Returns image hash entries within `max_distance` of `hash`, closest first
+    async fn find_image_hash(&self, hash: u64, max_distance: u32) -> Result<Vec<DbImageHashRow>>;
 }
 
 pub struct DatabaseFactory;
@@ -1,6 +1,8 @@
 use crate::{
     config::DatabaseConfig,
-    moderation::{ModerationCategories, ModerationService},
+    document::ImageInfo,
+    hashlist::HashListMatch,
+    moderation::{ModerationCategories, ModerationService, ProviderResult},
     utils::sha256,
 };
 use async_trait::async_trait;
@@ -14,7 +16,9 @@ use std::fs;
 use std::time::Duration;
 use uuid::Uuid;
 
-use super::{DatabaseProvider, DbModerationRow, DbReportRow, Result};
+use super::{
+    DatabaseProvider, DbImageHashRow, DbModerationRow, DbReportRow, ImageHashAction, Result,
+};
 
 #[derive(Clone)]
 pub struct PostgresDatabase {
@@ -118,6 +122,8 @@ impl DatabaseProvider for PostgresDatabase {
         provider: ModerationService,
         blocked: bool,
         categories: &[ModerationCategories],
+        provider_results: &[ProviderResult],
+        image_info: Option<&ImageInfo>,
     ) -> Result<()> {
         let url_hash = sha256(url.as_bytes());
         let timestamp = chrono::Utc::now();
@@ -125,15 +131,28 @@ impl DatabaseProvider for PostgresDatabase {
             serde_json::to_string(categories).unwrap_or_else(|_| String::from("json_error"));
         let provider_str =
             serde_json::to_string(&provider).unwrap_or_else(|_| String::from("json_error"));
+        let provider_results_str =
+            serde_json::to_string(provider_results).unwrap_or_else(|_| String::from("json_error"));
+        let image_info_str = image_info.and_then(|i| serde_json::to_string(i).ok());
         let conn = self.pool.get().await?;
         conn.execute(
             "UPDATE documents
-            SET blocked    = $1,
-                provider   = $2,
-                categories = $3,
-                updated_at = $4
-            WHERE url_hash = $5;",
-            &[&blocked, &provider_str, &cat_str, &timestamp, &url_hash],
+            SET blocked          = $1,
+                provider         = $2,
+                categories       = $3,
+                provider_results = $4,
+                image_info       = $5,
+                updated_at       = $6
+            WHERE url_hash = $7;",
+            &[
+                &blocked,
+                &provider_str,
+                &cat_str,
+                &provider_results_str,
+                &image_info_str,
+                &timestamp,
+                &url_hash,
+            ],
         )
         .await?;
         Ok(())
@@ -145,6 +164,8 @@ impl DatabaseProvider for PostgresDatabase {
         provider: ModerationService,
         blocked: bool,
         categories: &[ModerationCategories],
+        provider_results: &[ProviderResult],
+        image_info: Option<&ImageInfo>,
     ) -> Result<()> {
         let url_hash = sha256(url.as_bytes());
         let doc_hash = ""; //FIXME
@@ -153,11 +174,14 @@ impl DatabaseProvider for PostgresDatabase {
             serde_json::to_string(&provider).unwrap_or_else(|_| String::from("json_error"));
         let cat_str =
             serde_json::to_string(categories).unwrap_or_else(|_| String::from("json_error"));
+        let provider_results_str =
+            serde_json::to_string(provider_results).unwrap_or_else(|_| String::from("json_error"));
+        let image_info_str = image_info.and_then(|i| serde_json::to_string(i).ok());
         let conn = self.pool.get().await?;
-        conn.execute("INSERT INTO documents (url_hash, url, blocked, provider, categories, doc_hash, updated_at)
-        VALUES($1, $2, $3, $4, $5, $6, $7) 
+        conn.execute("INSERT INTO documents (url_hash, url, blocked, provider, categories, provider_results, image_info, doc_hash, updated_at)
+        VALUES($1, $2, $3, $4, $5, $6, $7, $8, $9) 
         ON CONFLICT (url_hash) 
-        DO NOTHING;", &[&url_hash, &url, &blocked, &provider_str, &cat_str, &doc_hash, &timestamp]).await?;
+        DO NOTHING;", &[&url_hash, &url, &blocked, &provider_str, &cat_str, &provider_results_str, &image_info_str, &doc_hash, &timestamp]).await?;
         Ok(())
     }
 
@@ -166,7 +190,7 @@ impl DatabaseProvider for PostgresDatabase {
         let conn = self.pool.get().await?;
         let results = conn
             .query(
-                "SELECT blocked, categories, provider, url from documents 
+                "SELECT blocked, categories, provider, provider_results, image_info, url from documents 
             WHERE documents.url_hash = ANY($1);",
                 &[&url_hashes],
             )
@@ -183,17 +207,124 @@ impl DatabaseProvider for PostgresDatabase {
                 let blocked: bool = r.get("blocked");
                 let categories: &str = r.get("categories");
                 let provider: &str = r.get("provider");
+                let provider_results: Option<&str> = r.get("provider_results");
+                let image_info: Option<&str> = r.get("image_info");
                 let url: &str = r.get("url");
 
                 let categories = serde_json::from_str::<Vec<ModerationCategories>>(categories)
                     .unwrap_or_default();
                 let provider = serde_json::from_str::<ModerationService>(provider)
                     .unwrap_or(ModerationService::Unknown);
+                let provider_results = provider_results
+                    .and_then(|p| serde_json::from_str::<Vec<ProviderResult>>(p).ok())
+                    .unwrap_or_default();
+                let image_info = image_info.and_then(|i| serde_json::from_str::<ImageInfo>(i).ok());
                 DbModerationRow {
                     blocked,
                     categories,
                     provider,
+                    provider_results,
                     url: String::from(url),
+                    image_info,
+                }
+            })
+            .collect())
+    }
+
+    async fn add_image_hash(
+        &self,
+        hash: u64,
+        action: ImageHashAction,
+        categories: &[ModerationCategories],
+    ) -> Result<()> {
+        // Postgres has no unsigned types, the bits are stored as is in a bigint
+        let hash = hash as i64;
+        let timestamp = chrono::Utc::now();
+        let action_str =
+            serde_json::to_string(&action).unwrap_or_else(|_| String::from("json_error"));
+        let cat_str =
+            serde_json::to_string(categories).unwrap_or_else(|_| String::from("json_error"));
+        let conn = self.pool.get().await?;
+        conn.execute(
+            "INSERT INTO image_hashes (hash, action, categories, updated_at)
+        VALUES($1, $2, $3, $4)
+        ON CONFLICT (hash)
+        DO UPDATE SET action = $2, categories = $3, updated_at = $4;",
+            &[&hash, &action_str, &cat_str, &timestamp],
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn add_hash_list_audit(
+        &self,
+        id: &Uuid,
+        url: &str,
+        doc_hash: &str,
+        hash_list_match: &HashListMatch,
+    ) -> Result<()> {
+        let id = id.to_string();
+        let url_hash = sha256(url.as_bytes());
+        let timestamp = chrono::Utc::now();
+        let match_type = hash_list_match.match_type.to_string();
+        let distance = hash_list_match.distance as i32;
+        let conn = self.pool.get().await?;
+        conn.execute(
+            "INSERT INTO hash_list_audit (id, url, url_hash, doc_hash, match_type, entry, reference, distance, created_at)
+        VALUES($1, $2, $3, $4, $5, $6, $7, $8, $9);",
+            &[
+                &id,
+                &url,
+                &url_hash,
+                &doc_hash,
+                &match_type,
+                &hash_list_match.entry,
+                &hash_list_match.reference,
+                &distance,
+                &timestamp,
+            ],
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn find_image_hash(&self, hash: u64, max_distance: u32) -> Result<Vec<DbImageHashRow>> {
+        let hash = hash as i64;
+        let max_distance = max_distance as i32;
+        let conn = self.pool.get().await?;
+        // Hamming distance is the number of set bits in the xor of both hashes.
+        // This scans the whole table which is fine for lists of moderate size.
+        let results = conn
+            .query(
+                "SELECT hash, action, categories, distance FROM (
+                SELECT hash, action, categories,
+                    length(replace(((hash # $1)::bit(64))::text, '0', '')) AS distance
+                FROM image_hashes) AS hashes
+            WHERE distance <= $2
+            ORDER BY distance ASC;",
+                &[&hash, &max_distance],
+            )
+            .await?;
+
+        debug!("Retrieved {} image hash rows.", results.len());
+        Ok(results
+            .iter()
+            .map(|r| {
+                let hash: i64 = r.get("hash");
+                let action: &str = r.get("action");
+                let categories: Option<&str> = r.get("categories");
+                let distance: i32 = r.get("distance");
+
+                let action = serde_json::from_str::<ImageHashAction>(action)
+                    .unwrap_or(ImageHashAction::Block);
+                let categories = categories
+                    .and_then(|c| serde_json::from_str::<Vec<ModerationCategories>>(c).ok())
+                    .unwrap_or_default();
+                DbImageHashRow {
+                    hash: hash as u64,
+                    action,
+                    categories,
+                    distance: distance as u32,
                 }
             })
             .collect())
@@ -1,16 +1,22 @@
 use crate::{
-    moderation::{ModerationCategories, ModerationService},
-    utils::sha256,
+    document::ImageInfo,
+    hashlist::{HashListMatch, HashListMatchType},
+    moderation::{ModerationCategories, ModerationService, ProviderResult},
+    utils::{hamming_distance, sha256},
 };
 use async_trait::async_trait;
 use std::{collections::HashMap, sync::Mutex};
 use uuid::Uuid;
 
-use super::{DatabaseProvider, DbModerationRow, DbReportRow, Result};
+use super::{
+    DatabaseProvider, DbImageHashRow, DbModerationRow, DbReportRow, ImageHashAction, Result,
+};
 
 pub struct DummyDatabase {
     report_store: Mutex<HashMap<String, DbReportRow>>,
     moderation_store: Mutex<HashMap<String, DbModerationRow>>,
+    image_hash_store: Mutex<HashMap<u64, DbImageHashRow>>,
+    hash_list_audit_store: Mutex<Vec<(String, HashListMatch)>>,
 }
 
 impl Default for DummyDatabase {
@@ -24,8 +30,15 @@ impl DummyDatabase {
         DummyDatabase {
             report_store: Mutex::new(HashMap::new()),
             moderation_store: Mutex::new(HashMap::new()),
+            image_hash_store: Mutex::new(HashMap::new()),
+            hash_list_audit_store: Mutex::new(Vec::new()),
         }
     }
+
+    /// Urls and matches recorded through `add_hash_list_audit`
+    pub fn hash_list_audits(&self) -> Vec<(String, HashListMatch)> {
+        self.hash_list_audit_store.lock().unwrap().clone()
+    }
 }
 
 #[async_trait]
@@ -65,9 +78,18 @@ impl DatabaseProvider for DummyDatabase {
         provider: ModerationService,
         blocked: bool,
         categories: &[ModerationCategories],
+        provider_results: &[ProviderResult],
+        image_info: Option<&ImageInfo>,
     ) -> Result<()> {
-        self.add_moderation_result(url, provider, blocked, categories)
-            .await
+        self.add_moderation_result(
+            url,
+            provider,
+            blocked,
+            categories,
+            provider_results,
+            image_info,
+        )
+        .await
     }
 
     async fn add_moderation_result(
@@ -76,13 +98,17 @@ impl DatabaseProvider for DummyDatabase {
         provider: ModerationService,
         blocked: bool,
         categories: &[ModerationCategories],
+        provider_results: &[ProviderResult],
+        image_info: Option<&ImageInfo>,
     ) -> Result<()> {
         let url_hash = sha256(url.as_bytes());
         let row = DbModerationRow {
             blocked,
             categories: Vec::from(categories),
             provider,
+            provider_results: Vec::from(provider_results),
             url: String::from(url),
+            image_info: image_info.cloned(),
         };
 
         let mut moderation_store = self.moderation_store.lock().unwrap();
@@ -105,6 +131,49 @@ impl DatabaseProvider for DummyDatabase {
             .collect();
         Ok(result)
     }
+
+    async fn add_image_hash(
+        &self,
+        hash: u64,
+        action: ImageHashAction,
+        categories: &[ModerationCategories],
+    ) -> Result<()> {
+        let row = DbImageHashRow {
+            hash,
+            action,
+            categories: Vec::from(categories),
+            distance: 0,
+        };
+        let mut image_hash_store = self.image_hash_store.lock().unwrap();
+        image_hash_store.insert(hash, row);
+        Ok(())
+    }
+
+    async fn add_hash_list_audit(
+        &self,
+        _id: &Uuid,
+        url: &str,
+        _doc_hash: &str,
+        hash_list_match: &HashListMatch,
+    ) -> Result<()> {
+        let mut hash_list_audit_store = self.hash_list_audit_store.lock().unwrap();
+        hash_list_audit_store.push((String::from(url), hash_list_match.clone()));
+        Ok(())
+    }
+
+    async fn find_image_hash(&self, hash: u64, max_distance: u32) -> Result<Vec<DbImageHashRow>> {
+        let image_hash_store = self.image_hash_store.lock().unwrap();
+        let mut result: Vec<DbImageHashRow> = image_hash_store
+            .values()
+            .map(|row| DbImageHashRow {
+                distance: hamming_distance(row.hash, hash),
+                ..row.clone()
+            })
+            .filter(|row| row.distance <= max_distance)
+            .collect();
+        result.sort_by_key(|row| row.distance);
+        Ok(result)
+    }
 }
 
 #[tokio::test]
@@ -120,6 +189,8 @@ async fn test_dummy_database_moderation_fns() {
             ModerationService::Unknown,
             true,
             &[ModerationCategories::Alcohol],
+            &[],
+            None,
         )
         .await;
     let result = db.get_moderation_result(&[url.clone()]).await.unwrap();
@@ -136,6 +207,11 @@ async fn test_dummy_database_moderation_fns() {
             ModerationService::Unknown,
             true,
             &[ModerationCategories::Alcohol, ModerationCategories::Drugs],
+            &[ProviderResult {
+                provider: ModerationService::Aws,
+                categories: vec![ModerationCategories::Drugs],
+            }],
+            None,
         )
         .await;
 
@@ -148,6 +224,8 @@ async fn test_dummy_database_moderation_fns() {
     assert_eq!(row.categories[1], ModerationCategories::Drugs);
     assert_eq!(row.url, url);
     assert_eq!(row.provider, ModerationService::Unknown);
+    assert_eq!(row.provider_results.len(), 1);
+    assert_eq!(row.provider_results[0].provider, ModerationService::Aws);
 }
 
 #[tokio::test]
@@ -167,3 +245,51 @@ async fn test_dummy_database_report_fns() {
     assert_eq!(row.url, url);
     assert_eq!(row.categories[0], ModerationCategories::Alcohol);
 }
+
+#[tokio::test]
+async fn test_dummy_database_image_hash_fns() {
+    let db = DummyDatabase::new();
+    let hash = 0xff00_ff00_ff00_ff00_u64;
+    let result = db.find_image_hash(hash, 4).await.unwrap();
+    assert_eq!(result.len(), 0);
+
+    let _ = db
+        .add_image_hash(hash, ImageHashAction::Block, &[ModerationCategories::Drugs])
+        .await;
+    let _ = db
+        .add_image_hash(hash ^ 0b111, ImageHashAction::Allow, &[])
+        .await;
+
+    // Two bits away from the first entry, one bit away from the second
+    let result = db.find_image_hash(hash ^ 0b011, 2).await.unwrap();
+    assert_eq!(result.len(), 2);
+    assert_eq!(result[0].action, ImageHashAction::Allow);
+    assert_eq!(result[0].distance, 1);
+    assert_eq!(result[1].action, ImageHashAction::Block);
+    assert_eq!(result[1].categories[0], ModerationCategories::Drugs);
+
+    let result = db.find_image_hash(hash ^ 0b011, 1).await.unwrap();
+    assert_eq!(result.len(), 1);
+}
+
+#[tokio::test]
+async fn test_dummy_database_hash_list_audit_fns() {
+    let db = DummyDatabase::new();
+    let url = "http://localhost/test.png".to_string();
+    let hash_list_match = HashListMatch {
+        match_type: HashListMatchType::Md5,
+        entry: "0123456789abcdef0123456789abcdef".to_string(),
+        reference: None,
+        distance: 0,
+    };
+    assert!(db.hash_list_audits().is_empty());
+
+    let result = db
+        .add_hash_list_audit(&Uuid::new_v4(), &url, "", &hash_list_match)
+        .await;
+    assert!(result.is_ok());
+    let audits = db.hash_list_audits();
+    assert_eq!(audits.len(), 1);
+    assert_eq!(audits[0].0, url);
+    assert_eq!(audits[0].1, hash_list_match);
+}
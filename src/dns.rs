@@ -1,19 +1,84 @@
+use std::io;
 use std::net::IpAddr;
+use std::time::{Duration, Instant};
 
-use dns_lookup::lookup_host;
+use async_trait::async_trait;
+use hickory_resolver::error::ResolveErrorKind;
+use hickory_resolver::system_conf::read_system_conf;
+use hickory_resolver::TokioAsyncResolver;
+use log::warn;
+use serde::Deserialize;
+
+use crate::metrics;
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct DnsConfig {
+    /// Number of resolved names kept, each for no longer than its TTL
+    pub cache_size: usize,
+    /// Lower bound in seconds on how long an answer is cached
+    pub min_ttl: Option<u64>,
+    /// Upper bound in seconds on how long an answer is cached
+    pub max_ttl: Option<u64>,
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        DnsConfig {
+            cache_size: 1024,
+            min_ttl: None,
+            max_ttl: None,
+        }
+    }
+}
 
 /// A trait that allows one to use different DNS implementations
+#[async_trait]
 pub trait DnsResolver {
-    fn resolve(&self, host: &str) -> Result<Vec<IpAddr>, std::io::Error>;
+    async fn resolve(&self, host: &str) -> Result<Vec<IpAddr>, io::Error>;
 }
 
-/// A DNS resolver based on the crate dns_lookup
-#[derive(Clone)]
-pub struct StandardDnsResolver {}
+/// An async resolver using the system's name servers. Answers are cached
+/// for as long as their TTL allows, a single instance is shared between the
+/// uri filters and the http connector.
+pub struct StandardDnsResolver {
+    resolver: TokioAsyncResolver,
+}
 
+impl StandardDnsResolver {
+    pub fn new(config: &DnsConfig) -> Result<Self, io::Error> {
+        let (resolver_config, mut options) = read_system_conf()?;
+        options.cache_size = config.cache_size;
+        options.positive_min_ttl = config.min_ttl.map(Duration::from_secs);
+        options.positive_max_ttl = config.max_ttl.map(Duration::from_secs);
+        options.negative_max_ttl = config.max_ttl.map(Duration::from_secs);
+        Ok(StandardDnsResolver {
+            resolver: TokioAsyncResolver::tokio(resolver_config, options),
+        })
+    }
+}
+
+#[async_trait]
 impl DnsResolver for StandardDnsResolver {
-    fn resolve(&self, host: &str) -> Result<Vec<IpAddr>, std::io::Error> {
-        lookup_host(host)
+    async fn resolve(&self, host: &str) -> Result<Vec<IpAddr>, io::Error> {
+        let start = Instant::now();
+        let result = self.resolver.lookup_ip(host).await;
+        let outcome = if result.is_ok() { "success" } else { "failure" };
+        metrics::DNS_LOOKUP_TIME
+            .with_label_values(&[outcome])
+            .observe(start.elapsed().as_secs_f64() * 1000_f64);
+
+        result.map(|lookup| lookup.iter().collect()).map_err(|e| {
+            warn!("DNS lookup failed, host={}, reason={}", host, e);
+            let (reason, kind) = match e.kind() {
+                ResolveErrorKind::NoRecordsFound { .. } => ("no_records", io::ErrorKind::NotFound),
+                ResolveErrorKind::Timeout => ("timeout", io::ErrorKind::TimedOut),
+                _ => ("error", io::ErrorKind::Other),
+            };
+            metrics::DNS_LOOKUP_FAILURES
+                .with_label_values(&[reason])
+                .inc();
+            io::Error::new(kind, e)
+        })
     }
 }
 
@@ -22,8 +87,9 @@ pub struct DummyDnsResolver {
     pub resolved_address: Vec<IpAddr>,
 }
 
+#[async_trait]
 impl DnsResolver for DummyDnsResolver {
-    fn resolve(&self, _host: &str) -> Result<Vec<IpAddr>, std::io::Error> {
+    async fn resolve(&self, _host: &str) -> Result<Vec<IpAddr>, io::Error> {
         Ok(self.resolved_address.to_owned())
     }
 }
@@ -32,8 +98,8 @@ impl DnsResolver for DummyDnsResolver {
 mod tests {
     use super::*;
 
-    #[test]
-    fn test_dummy_dns_resolver() {
+    #[tokio::test]
+    async fn test_dummy_dns_resolver() {
         let ip: IpAddr = "127.0.0.1".parse().unwrap();
         let ip2: IpAddr = "10.0.0.2".parse().unwrap();
         let ip_vec = vec![ip, ip2];
@@ -41,7 +107,7 @@ mod tests {
             resolved_address: ip_vec,
         };
         let hostname = String::from("some_host_name");
-        let resolved_results = resolver.resolve(&hostname).unwrap();
+        let resolved_results = resolver.resolve(&hostname).await.unwrap();
         assert!(resolved_results.contains(&ip));
         assert!(resolved_results.contains(&ip2));
         assert_eq!(resolved_results.len(), 2);
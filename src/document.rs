@@ -1,16 +1,31 @@
 extern crate base64;
 extern crate hyper;
 
+use crate::blurhash;
+use crate::metadata;
 use crate::metrics;
+use crate::moderation::SupportedMimeTypes;
 use crate::rpc::error::Errors;
-use image::io::Reader as ImageReader;
+use crate::rpc::requests::{FitMode, OutputFormat};
+use crate::svg;
+use image::io::{Limits, Reader as ImageReader};
+use std::collections::HashMap;
 use std::io::Cursor;
+use std::time::Instant;
 
 use base64::prelude::*;
 use hyper::body::Bytes;
 
-use image::{DynamicImage, GenericImageView, ImageOutputFormat};
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::imageops::FilterType;
+use image::{
+    AnimationDecoder, ColorType, DynamicImage, Frames, GenericImageView, ImageDecoder, ImageError,
+    ImageFormat, ImageOutputFormat, Rgb, RgbImage, RgbaImage,
+};
 use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 // The X or Y resolution (depending on aspect ration) that is
@@ -20,6 +35,190 @@ const NOMINAL_IMAGE_DIMENSION: u32 = 1024_u32;
 // The minimum dimension for either X or Y
 const MINIMUM_IMAGE_DIMENSION: u32 = 128_u32;
 
+// Qualities used when re-encoding images without a requested quality
+const JPEG_QUALITY: u8 = 85_u8;
+const WEBP_QUALITY: u8 = 80_u8;
+
+// Moderation resizing aims for this fraction of the maximum size, as the
+// size at the target dimensions is estimated from a small sample
+const RESIZE_SIZE_MARGIN: f64 = 0.9_f64;
+const RESIZE_SAMPLE_DIMENSION: u32 = 256_u32;
+// Further shrinking attempts when an estimate turns out too large
+const RESIZE_MAX_ATTEMPTS: u32 = 4_u32;
+
+// Placeholders for blocked images keep no more detail than an image of
+// this width and height, and are at most `PLACEHOLDER_DIMENSION` in size
+const PLACEHOLDER_DETAIL: u32 = 16_u32;
+const PLACEHOLDER_DIMENSION: u32 = 256_u32;
+
+// The dominant color is picked from a thumbnail at most this size
+const DOMINANT_COLOR_SAMPLE: u32 = 64_u32;
+
+// Cover and Fill transforms enlarge an image at most this many times in
+// either dimension
+const MAX_TRANSFORM_UPSCALE: u32 = 4_u32;
+
+// Number of leading bytes inspected when looking for an svg element
+const SVG_SNIFF_LENGTH: usize = 1024_usize;
+
+/// Resizing and re-encoding requested by a client
+#[derive(Clone, Debug, Default)]
+pub struct ImageTransform {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fit: FitMode,
+    pub format: Option<OutputFormat>,
+    /// Encoding quality from 1 to 100, ignored for PNG
+    pub quality: Option<u8>,
+}
+
+/// Properties of a fetched image that clients can lay out galleries with
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ImageInfo {
+    pub width: u32,
+    pub height: u32,
+    /// Source format, such as `jpeg`, `png` or `svg`
+    pub format: String,
+    /// Size in bytes of the document as served
+    pub size: u64,
+    /// Number of frames, 1 for still images
+    pub frames: u32,
+    /// Most common color as `#rrggbb`
+    pub dominant_color: String,
+}
+
+/// Resampling filters, from fastest to best quality
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResizeFilter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    Lanczos3,
+}
+
+impl From<ResizeFilter> for FilterType {
+    fn from(filter: ResizeFilter) -> Self {
+        match filter {
+            ResizeFilter::Nearest => FilterType::Nearest,
+            ResizeFilter::Triangle => FilterType::Triangle,
+            ResizeFilter::CatmullRom => FilterType::CatmullRom,
+            ResizeFilter::Gaussian => FilterType::Gaussian,
+            ResizeFilter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+/// How images are shrunk to fit the size limit of the moderation provider
+#[derive(Deserialize, Clone, Debug)]
+pub struct ResizeConfig {
+    pub filter: ResizeFilter,
+    /// JPEG quality tried first, when the provider accepts JPEG
+    pub jpeg_quality: u8,
+    /// Lowest JPEG quality tried before the image is shrunk further
+    pub min_jpeg_quality: u8,
+    pub jpeg_quality_step: u8,
+}
+
+impl Default for ResizeConfig {
+    fn default() -> Self {
+        ResizeConfig {
+            filter: ResizeFilter::Triangle,
+            jpeg_quality: JPEG_QUALITY,
+            min_jpeg_quality: 60,
+            jpeg_quality_step: 10,
+        }
+    }
+}
+
+impl ResizeConfig {
+    /// JPEG qualities to try, highest first
+    fn jpeg_qualities(&self) -> Vec<u8> {
+        let mut qualities: Vec<u8> = (self.min_jpeg_quality..=self.jpeg_quality)
+            .rev()
+            .step_by(self.jpeg_quality_step.max(1) as usize)
+            .collect();
+        if qualities.is_empty() {
+            qualities.push(self.jpeg_quality);
+        }
+        qualities
+    }
+}
+
+/// Limits checked against the image header before an image is decoded,
+/// guarding against decompression bombs
+#[derive(Deserialize, Clone, Debug)]
+pub struct ImageLimits {
+    pub max_width: u32,
+    pub max_height: u32,
+    /// Maximum number of pixels, i.e. width times height
+    pub max_pixels: u64,
+    /// Maximum number of bytes the decoder may allocate
+    pub max_alloc: u64,
+    /// Maximum number of frames of an animated image
+    pub max_frames: u32,
+}
+
+impl Default for ImageLimits {
+    fn default() -> Self {
+        ImageLimits {
+            max_width: 16384,
+            max_height: 16384,
+            max_pixels: 64_000_000,
+            max_alloc: 512 * 1024 * 1024,
+            max_frames: 1000,
+        }
+    }
+}
+
+impl ImageLimits {
+    /// Name of the first limit the dimensions exceed, if any
+    pub fn exceeded(&self, width: u32, height: u32) -> Option<&'static str> {
+        if width > self.max_width {
+            Some("width")
+        } else if height > self.max_height {
+            Some("height")
+        } else if width as u64 * height as u64 > self.max_pixels {
+            Some("pixels")
+        } else {
+            None
+        }
+    }
+
+    fn check(&self, id: &Uuid, width: u32, height: u32) -> Result<(), Errors> {
+        let exceeded = match self.exceeded(width, height) {
+            Some(exceeded) => exceeded,
+            None => return Ok(()),
+        };
+        warn!(
+            "Image exceeds {} limit, id={}, width={}, height={}",
+            exceeded, id, width, height
+        );
+        metrics::IMAGE_LIMITS.with_label_values(&[exceeded]).inc();
+        Err(Errors::ImageTooLarge)
+    }
+
+    fn check_frames(&self, id: &Uuid, frames: usize) -> Result<(), Errors> {
+        if frames <= self.max_frames as usize {
+            return Ok(());
+        }
+        warn!(
+            "Animation exceeds frames limit, id={}, max_frames={}",
+            id, self.max_frames
+        );
+        metrics::IMAGE_LIMITS.with_label_values(&["frames"]).inc();
+        Err(Errors::ImageTooLarge)
+    }
+
+    fn decoder_limits(&self) -> Limits {
+        let mut limits = Limits::default();
+        limits.max_image_width = Some(self.max_width);
+        limits.max_image_height = Some(self.max_height);
+        limits.max_alloc = Some(self.max_alloc);
+        limits
+    }
+}
+
 #[derive(Clone)]
 pub struct Document {
     pub id: Uuid,
@@ -29,20 +228,73 @@ pub struct Document {
     pub url: String,
 }
 
+fn observe_resize_time(stage: &str, start: Instant) {
+    metrics::IMAGE_RESIZE_TIME
+        .with_label_values(&[stage])
+        .observe(start.elapsed().as_secs_f64() * 1000_f64);
+}
+
 impl Document {
-    fn load_image(&self) -> Result<DynamicImage, Errors> {
-        ImageReader::new(Cursor::new(&self.bytes))
+    fn image_reader(&self) -> Result<ImageReader<Cursor<&Bytes>>, Errors> {
+        let mut reader = ImageReader::new(Cursor::new(&self.bytes))
             .with_guessed_format()
             .map_err(|e| {
                 error!("Unable to open image, id={}, reason={}", self.id, e);
                 Errors::ImageResizeError
-            })
-            .and_then(|r| {
-                r.decode().map_err(|e| {
-                    error!("Unable to open image, id={}, reason={}", self.id, e);
+            })?;
+        // Not every AVIF brand is recognised from its magic bytes
+        if reader.format().is_none() {
+            if let Some(format) = ImageFormat::from_mime_type(&self.content_type) {
+                reader.set_format(format);
+            }
+        }
+        Ok(reader)
+    }
+
+    /// Checks the dimensions declared in the image header against the
+    /// limits without decoding the image. SVGs are always rendered at a
+    /// fixed size and are not checked, their embedded images are checked
+    /// when rendering.
+    pub fn check_limits(&self, limits: &ImageLimits) -> Result<(), Errors> {
+        if self.is_svg() {
+            return Ok(());
+        }
+        let (width, height) = self.image_reader()?.into_dimensions().map_err(|e| {
+            error!("Unable to read image header, id={}, reason={}", self.id, e);
+            Errors::ImageResizeError
+        })?;
+        limits.check(&self.id, width, height)
+    }
+
+    /// Decodes the image within the limits, SVGs are rasterized
+    pub fn load_image(&self, limits: &ImageLimits) -> Result<DynamicImage, Errors> {
+        if self.is_svg() {
+            return svg::rasterize(&self.bytes, NOMINAL_IMAGE_DIMENSION, limits)
+                .map(DynamicImage::ImageRgba8)
+                .map_err(|e| {
+                    error!("Unable to rasterize svg, id={}, reason={}", self.id, e);
                     Errors::ImageResizeError
-                })
-            })
+                });
+        }
+        self.check_limits(limits)?;
+        let mut reader = self.image_reader()?;
+        reader.limits(limits.decoder_limits());
+        reader.decode().map_err(|e| self.decode_error(e))
+    }
+
+    /// Maps a decoder error, reporting exceeded decoder limits as such
+    fn decode_error(&self, e: ImageError) -> Errors {
+        match e {
+            ImageError::Limits(e) => {
+                warn!("Image exceeds alloc limit, id={}, reason={}", self.id, e);
+                metrics::IMAGE_LIMITS.with_label_values(&["alloc"]).inc();
+                Errors::ImageTooLarge
+            }
+            e => {
+                error!("Unable to open image, id={}, reason={}", self.id, e);
+                Errors::ImageResizeError
+            }
+        }
     }
 
     fn resize_parameters(x_dim: u32, y_dim: u32, target_dim: u32, min_dim: u32) -> (u32, u32) {
@@ -74,55 +326,84 @@ impl Document {
         }
     }
 
-    fn resize(&self, img: DynamicImage, target_dim: u32, max_size: u64) -> Result<Vec<u8>, Errors> {
-        metrics::IMAGE_RESIZE.with_label_values(&["request"]).inc();
+    /// Estimates the largest dimension at which the encoded image fits in
+    /// `max_size`, from the bytes per pixel of an encoded sample
+    fn estimate_target_dimension(
+        &self,
+        img: &DynamicImage,
+        format: OutputFormat,
+        quality: Option<u8>,
+        max_size: u64,
+    ) -> Result<u32, Errors> {
         let (x_dim, y_dim) = img.dimensions();
+        let target_dim = NOMINAL_IMAGE_DIMENSION.min(x_dim.max(y_dim));
+        if x_dim.max(y_dim) <= RESIZE_SAMPLE_DIMENSION {
+            return Ok(target_dim);
+        }
+
+        let start = Instant::now();
+        let sample = img.resize(
+            RESIZE_SAMPLE_DIMENSION,
+            RESIZE_SAMPLE_DIMENSION,
+            FilterType::Triangle,
+        );
+        let encoded = self.encode_image(&sample, format, quality)?;
+        observe_resize_time("estimate", start);
+
+        let bytes_per_pixel =
+            encoded.bytes.len() as f64 / (sample.width() * sample.height()) as f64;
         let (new_x_dim, new_y_dim) =
             Self::resize_parameters(x_dim, y_dim, target_dim, MINIMUM_IMAGE_DIMENSION);
-        info!(
-            "Image resizing, id={}, x={}, y={}, new_x={}, new_y={}",
-            self.id, x_dim, y_dim, new_x_dim, new_y_dim
-        );
-        let new_img = img.resize(new_x_dim, new_y_dim, image::imageops::FilterType::Nearest);
-        let mut cursor = Cursor::new(Vec::new());
-        match new_img.write_to(&mut cursor, ImageOutputFormat::Png) {
-            Ok(_) => {
-                let bytes = cursor.into_inner();
-                info!(
-                    "Image resizing result, id={}, len={}, new_len={}",
-                    self.id,
-                    self.bytes.len(),
-                    bytes.len()
-                );
-                if bytes.len() as u64 > max_size {
-                    metrics::IMAGE_RESIZE.with_label_values(&["retry"]).inc();
-                    warn!("Resizing did not reduce image size enough to fit max moderation size, id={}, max_size={}", self.id, max_size);
-                    if target_dim / 2_u32 < MINIMUM_IMAGE_DIMENSION {
-                        metrics::IMAGE_RESIZE
-                            .with_label_values(&["dim_floor_hit"])
-                            .inc();
-                        warn!("Image dimension(s) is smaller than {} pixels but file size is greater than max moderation size, id={}, max_size={}", MINIMUM_IMAGE_DIMENSION, self.id, max_size );
-                        Ok(bytes)
-                    } else {
-                        self.resize(new_img, target_dim / 2_u32, max_size)
-                    }
-                } else {
-                    metrics::IMAGE_RESIZE.with_label_values(&["success"]).inc();
-                    Ok(bytes)
-                }
-            }
-            Err(e) => {
-                metrics::IMAGE_RESIZE.with_label_values(&["failed"]).inc();
-                error!(
-                    "Error writing out image to buffer, id={}, reason={}",
-                    self.id, e
-                );
-                Err(Errors::ImageResizeError)
+        let estimate = bytes_per_pixel * new_x_dim as f64 * new_y_dim as f64;
+        let budget = max_size as f64 * RESIZE_SIZE_MARGIN;
+        if estimate <= budget {
+            return Ok(target_dim);
+        }
+        metrics::IMAGE_RESIZE
+            .with_label_values(&["estimate_shrink"])
+            .inc();
+        let scale = (budget / estimate).sqrt();
+        Ok(((target_dim as f64 * scale).floor() as u32).max(1))
+    }
+
+    /// Encodes the resized image, stepping down through the qualities until
+    /// it fits. Returns the last attempt if none fits.
+    fn encode_within(
+        &self,
+        img: &DynamicImage,
+        format: OutputFormat,
+        qualities: &[Option<u8>],
+        max_size: u64,
+    ) -> Result<Document, Errors> {
+        let mut encoded = None;
+        for quality in qualities {
+            let start = Instant::now();
+            let document = self
+                .encode_image(img, format, *quality)
+                .inspect_err(|_| metrics::IMAGE_RESIZE.with_label_values(&["failed"]).inc())?;
+            observe_resize_time("encode", start);
+            if document.bytes.len() as u64 <= max_size {
+                return Ok(document);
             }
+            metrics::IMAGE_RESIZE
+                .with_label_values(&["quality_step"])
+                .inc();
+            encoded = Some(document);
         }
+        encoded.ok_or(Errors::ImageResizeError)
     }
 
-    pub fn resize_image(&self, max_size: u64) -> Result<Document, Errors> {
+    /// Shrinks the image to fit within `max_size` bytes for moderation,
+    /// encoding it as JPEG if allowed and PNG otherwise. Target dimensions
+    /// are estimated up front, then the JPEG quality is stepped down before
+    /// the image is shrunk any further.
+    pub fn resize_image(
+        &self,
+        max_size: u64,
+        allow_jpeg: bool,
+        config: &ResizeConfig,
+        limits: &ImageLimits,
+    ) -> Result<Document, Errors> {
         info!(
             "Image info, id={}, len={}, type={}",
             self.id,
@@ -141,18 +422,601 @@ impl Document {
                 .with_label_values(&["format_change"])
                 .inc();
         }
+        metrics::IMAGE_RESIZE.with_label_values(&["request"]).inc();
 
-        let img = self.load_image()?;
-        let bytes = self.resize(img, NOMINAL_IMAGE_DIMENSION, max_size)?;
+        let start = Instant::now();
+        let img = self.load_image(limits)?;
+        observe_resize_time("decode", start);
+
+        let (format, qualities) = if allow_jpeg {
+            let qualities = config.jpeg_qualities().into_iter().map(Some).collect();
+            (OutputFormat::Jpeg, qualities)
+        } else {
+            (OutputFormat::Png, vec![None])
+        };
+        let (x_dim, y_dim) = img.dimensions();
+        let mut target_dim =
+            self.estimate_target_dimension(&img, format, qualities[0], max_size)?;
+        let mut attempts = 0_u32;
+
+        loop {
+            let (new_x_dim, new_y_dim) =
+                Self::resize_parameters(x_dim, y_dim, target_dim, MINIMUM_IMAGE_DIMENSION);
+            info!(
+                "Image resizing, id={}, x={}, y={}, new_x={}, new_y={}, format={:?}",
+                self.id, x_dim, y_dim, new_x_dim, new_y_dim, format
+            );
+            let resize_start = Instant::now();
+            let resized = img.resize_exact(new_x_dim, new_y_dim, config.filter.into());
+            observe_resize_time("resize", resize_start);
+
+            let document = self.encode_within(&resized, format, &qualities, max_size)?;
+            info!(
+                "Image resizing result, id={}, len={}, new_len={}",
+                self.id,
+                self.bytes.len(),
+                document.bytes.len()
+            );
+            if document.bytes.len() as u64 <= max_size {
+                metrics::IMAGE_RESIZE.with_label_values(&["success"]).inc();
+                observe_resize_time("total", start);
+                return Ok(document);
+            }
+
+            metrics::IMAGE_RESIZE.with_label_values(&["retry"]).inc();
+            warn!("Resizing did not reduce image size enough to fit max moderation size, id={}, max_size={}", self.id, max_size);
+            let scale = (max_size as f64 / document.bytes.len() as f64 * RESIZE_SIZE_MARGIN).sqrt();
+            let next_dim = (new_x_dim.max(new_y_dim) as f64 * scale).floor() as u32;
+            let at_floor = Self::resize_parameters(x_dim, y_dim, next_dim, MINIMUM_IMAGE_DIMENSION)
+                == (new_x_dim, new_y_dim);
+            if at_floor {
+                metrics::IMAGE_RESIZE
+                    .with_label_values(&["dim_floor_hit"])
+                    .inc();
+                warn!("Image dimension(s) is smaller than {} pixels but file size is greater than max moderation size, id={}, max_size={}", MINIMUM_IMAGE_DIMENSION, self.id, max_size );
+                observe_resize_time("total", start);
+                return Ok(document);
+            }
+            if attempts == RESIZE_MAX_ATTEMPTS {
+                metrics::IMAGE_RESIZE
+                    .with_label_values(&["attempts_exhausted"])
+                    .inc();
+                warn!("Image still larger than max moderation size after {} resize attempts, id={}, max_size={}, len={}", RESIZE_MAX_ATTEMPTS + 1, self.id, max_size, document.bytes.len());
+                observe_resize_time("total", start);
+                return Ok(document);
+            }
+            attempts += 1;
+            target_dim = next_dim;
+        }
+    }
+
+    /// Computes a 64 bit difference hash (dHash) of the image. Visually
+    /// similar images, such as re-encodes or rescales of the same picture,
+    /// have hashes with a small hamming distance.
+    pub fn perceptual_hash(&self, limits: &ImageLimits) -> Result<u64, Errors> {
+        let img = self.load_image(limits)?;
+        Ok(Self::difference_hash(&img))
+    }
+
+    pub fn difference_hash(img: &DynamicImage) -> u64 {
+        let small = img.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+        let mut hash = 0_u64;
+        for y in 0..8 {
+            for x in 0..8 {
+                let left = small.get_pixel(x, y)[0];
+                let right = small.get_pixel(x + 1, y)[0];
+                hash = (hash << 1) | u64::from(left > right);
+            }
+        }
+        hash
+    }
+
+    /// Picks the most common color, grouping similar colors together.
+    /// Mostly transparent pixels are ignored unless there are no others.
+    fn dominant_color(img: &DynamicImage) -> String {
+        let thumbnail = img
+            .thumbnail(DOMINANT_COLOR_SAMPLE, DOMINANT_COLOR_SAMPLE)
+            .to_rgba8();
+        let opaque = thumbnail.pixels().any(|p| p[3] >= 128);
+        // Colors are bucketed by the upper 4 bits of each channel, summing
+        // the count and channels of each bucket
+        let mut buckets: HashMap<u32, [u32; 4]> = HashMap::new();
+        for pixel in thumbnail.pixels().filter(|p| !opaque || p[3] >= 128) {
+            let [r, g, b, _] = pixel.0;
+            let key = (r as u32 >> 4) << 8 | (g as u32 >> 4) << 4 | b as u32 >> 4;
+            let bucket = buckets.entry(key).or_default();
+            bucket[0] += 1;
+            bucket[1] += r as u32;
+            bucket[2] += g as u32;
+            bucket[3] += b as u32;
+        }
+        match buckets
+            .iter()
+            .max_by_key(|(key, bucket)| (bucket[0], **key))
+        {
+            Some((_, [count, r, g, b])) => {
+                format!("#{:02x}{:02x}{:02x}", r / count, g / count, b / count)
+            }
+            None => String::from("#000000"),
+        }
+    }
+
+    /// Describes the image for clients from the already decoded image.
+    /// Dimensions and format are read from the header, SVGs report the
+    /// dimensions they are rasterized at. Frames are only counted if the
+    /// count is not already known.
+    pub fn image_info(
+        &self,
+        img: &DynamicImage,
+        frames: Option<u32>,
+        limits: &ImageLimits,
+    ) -> Result<ImageInfo, Errors> {
+        let (format, (width, height)) = if self.is_svg() {
+            (String::from("svg"), img.dimensions())
+        } else {
+            let reader = self.image_reader()?;
+            let format = reader
+                .format()
+                .map(|f| format!("{:?}", f).to_ascii_lowercase())
+                .unwrap_or_default();
+            let dimensions = reader.into_dimensions().map_err(|e| {
+                error!("Unable to read image header, id={}, reason={}", self.id, e);
+                Errors::ImageResizeError
+            })?;
+            (format, dimensions)
+        };
+        let frames = match frames {
+            Some(frames) => frames,
+            None => self.frame_count(limits)?,
+        };
+        Ok(ImageInfo {
+            width,
+            height,
+            format,
+            size: self.bytes.len() as u64,
+            frames,
+            dominant_color: Self::dominant_color(img),
+        })
+    }
+
+    /// Counts the frames of an animated image, 1 for any other image
+    fn frame_count(&self, limits: &ImageLimits) -> Result<u32, Errors> {
+        if self.is_svg() {
+            return Ok(1);
+        }
+        let frames = match self.animation_frames(limits)? {
+            Some(frames) => frames.take(limits.max_frames as usize + 1).count(),
+            None => return Ok(1),
+        };
+        limits.check_frames(&self.id, frames)?;
+        Ok(frames.max(1) as u32)
+    }
+
+    /// Returns a frame iterator for animated GIFs, APNGs and WebPs, `None` for
+    /// any other image. Frames are decoded within the same limits as still
+    /// images.
+    fn animation_frames(&self, limits: &ImageLimits) -> Result<Option<Frames<'_>>, Errors> {
+        let map_err = |e| self.decode_error(e);
+        match image::guess_format(&self.bytes) {
+            Ok(ImageFormat::Gif) => {
+                let mut decoder =
+                    GifDecoder::new(Cursor::new(self.bytes.as_ref())).map_err(map_err)?;
+                let (width, height) = decoder.dimensions();
+                limits.check(&self.id, width, height)?;
+                decoder
+                    .set_limits(limits.decoder_limits())
+                    .map_err(map_err)?;
+                Ok(Some(decoder.into_frames()))
+            }
+            Ok(ImageFormat::Png) => {
+                let mut decoder =
+                    PngDecoder::new(Cursor::new(self.bytes.as_ref())).map_err(map_err)?;
+                let (width, height) = decoder.dimensions();
+                limits.check(&self.id, width, height)?;
+                decoder
+                    .set_limits(limits.decoder_limits())
+                    .map_err(map_err)?;
+                if decoder.is_apng() {
+                    Ok(Some(decoder.apng().into_frames()))
+                } else {
+                    Ok(None)
+                }
+            }
+            Ok(ImageFormat::WebP) => {
+                let mut decoder =
+                    WebPDecoder::new(Cursor::new(self.bytes.as_ref())).map_err(map_err)?;
+                let (width, height) = decoder.dimensions();
+                limits.check(&self.id, width, height)?;
+                // The WebP decoder ignores the allocation limit, frames are
+                // composited onto a canvas of the full image size
+                let mut decoder_limits = limits.decoder_limits();
+                decoder_limits
+                    .reserve_buffer(width, height, ColorType::Rgba8)
+                    .map_err(map_err)?;
+                decoder.set_limits(decoder_limits).map_err(map_err)?;
+                if decoder.has_animation() {
+                    Ok(Some(decoder.into_frames()))
+                } else {
+                    Ok(None)
+                }
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Decodes at most `max_frames` frames spread evenly over an animated
+    /// image in a single pass, along with the number of frames. Returns
+    /// `None` if the image is not animated, and fails for animations with
+    /// more frames than the limits allow.
+    pub fn sample_frames(
+        &self,
+        max_frames: usize,
+        limits: &ImageLimits,
+    ) -> Result<Option<(Vec<RgbaImage>, u32)>, Errors> {
+        if max_frames == 0 {
+            return Ok(None);
+        }
+        let frames = match self.animation_frames(limits)? {
+            Some(frames) => frames,
+            None => return Ok(None),
+        };
+
+        // Every `stride`th frame is kept. When more than `max_frames` would
+        // be kept every other one is dropped and the stride doubled, so the
+        // sampled frames stay spread over the whole animation.
+        let mut stride = 1;
+        let mut frame_count = 0;
+        let mut sampled = Vec::with_capacity(max_frames);
+        for (index, frame) in frames.enumerate() {
+            limits.check_frames(&self.id, index + 1)?;
+            frame_count = index + 1;
+            if index % stride != 0 {
+                continue;
+            }
+            if sampled.len() == max_frames {
+                sampled = sampled.into_iter().step_by(2).collect();
+                stride *= 2;
+                if index % stride != 0 {
+                    continue;
+                }
+            }
+            let frame = frame.map_err(|e| self.decode_error(e))?;
+            sampled.push(frame.into_buffer());
+        }
+        if frame_count <= 1 {
+            return Ok(None);
+        }
+        info!(
+            "Sampled animation frames, id={}, frames={}, sampled={}",
+            self.id,
+            frame_count,
+            sampled.len()
+        );
+        Ok(Some((sampled, frame_count as u32)))
+    }
+
+    /// Tiles frames into a grid no larger than the nominal image dimension
+    fn contact_sheet(frames: &[RgbaImage]) -> RgbaImage {
+        let (width, height) = frames.first().map(|f| f.dimensions()).unwrap_or((1, 1));
+        let columns = (frames.len() as f64).sqrt().ceil().max(1_f64) as u32;
+        let rows = (frames.len() as u32).div_ceil(columns);
+        let scale = (NOMINAL_IMAGE_DIMENSION as f64 / (columns * width) as f64)
+            .min(NOMINAL_IMAGE_DIMENSION as f64 / (rows * height) as f64)
+            .min(1_f64);
+        let cell_width = ((width as f64 * scale).floor() as u32).max(1);
+        let cell_height = ((height as f64 * scale).floor() as u32).max(1);
+
+        let mut sheet = RgbaImage::new(cell_width * columns, cell_height * rows);
+        for (index, frame) in frames.iter().enumerate() {
+            let tile =
+                image::imageops::resize(frame, cell_width, cell_height, FilterType::Triangle);
+            let x = (index as u32 % columns) * cell_width;
+            let y = (index as u32 / columns) * cell_height;
+            image::imageops::replace(&mut sheet, &tile, x as i64, y as i64);
+        }
+        sheet
+    }
+
+    fn image_document(&self, img: RgbaImage) -> Result<Document, Errors> {
+        self.encode_image(&DynamicImage::ImageRgba8(img), OutputFormat::Png, None)
+    }
+
+    /// Composites the image onto a white background. Dropping the alpha
+    /// channel instead reveals whatever color transparent pixels happen to
+    /// hold, often black.
+    fn flatten_alpha(img: &DynamicImage) -> RgbImage {
+        if !img.color().has_alpha() {
+            return img.to_rgb8();
+        }
+        let rgba = img.to_rgba8();
+        RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+            let [r, g, b, a] = rgba.get_pixel(x, y).0;
+            let blend = |c: u8| ((c as u32 * a as u32 + 255 * (255 - a as u32)) / 255) as u8;
+            Rgb([blend(r), blend(g), blend(b)])
+        })
+    }
+
+    fn encode_image(
+        &self,
+        img: &DynamicImage,
+        format: OutputFormat,
+        quality: Option<u8>,
+    ) -> Result<Document, Errors> {
+        let mut cursor = Cursor::new(Vec::new());
+        let (written, content_type) = match format {
+            // Lossy WebP is not supported by the image crate, so libwebp is used
+            OutputFormat::Webp => {
+                let rgba = img.to_rgba8();
+                let quality = quality.unwrap_or(WEBP_QUALITY);
+                let encoded = webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height())
+                    .encode(quality as f32);
+                cursor.get_mut().extend_from_slice(&encoded);
+                (Ok(()), "image/webp")
+            }
+            // The encoder does not handle every color type, so convert first
+            OutputFormat::Jpeg => (
+                DynamicImage::ImageRgb8(Self::flatten_alpha(img)).write_to(
+                    &mut cursor,
+                    ImageOutputFormat::Jpeg(quality.unwrap_or(JPEG_QUALITY)),
+                ),
+                "image/jpeg",
+            ),
+            OutputFormat::Png | OutputFormat::Auto => (
+                img.write_to(&mut cursor, ImageOutputFormat::Png),
+                "image/png",
+            ),
+        };
+        written.map_err(|e| {
+            error!(
+                "Error writing out image to buffer, id={}, reason={}",
+                self.id, e
+            );
+            Errors::ImageResizeError
+        })?;
+        let bytes = cursor.into_inner();
         Ok(Document {
             id: self.id,
             content_length: bytes.len() as u64,
-            content_type: String::from("image/png"),
-            bytes: Bytes::copy_from_slice(bytes.as_slice()),
+            content_type: String::from(content_type),
+            bytes: Bytes::from(bytes),
             url: self.url.clone(),
         })
     }
 
+    /// Resizes and/or re-encodes the image as requested by a client. With a
+    /// single dimension the aspect ratio is kept; images are only enlarged
+    /// when both dimensions are given with `Cover` or `Fill`. Without a
+    /// format, JPEGs stay JPEGs and everything else becomes PNG.
+    pub fn transform(
+        &self,
+        transform: &ImageTransform,
+        limits: &ImageLimits,
+    ) -> Result<Document, Errors> {
+        metrics::IMAGE_RESIZE
+            .with_label_values(&["transform"])
+            .inc();
+        let img = self.load_image(limits)?;
+        let (x_dim, y_dim) = img.dimensions();
+        let filter = FilterType::Triangle;
+        let img = match (transform.width, transform.height) {
+            (Some(w), Some(h)) => match transform.fit {
+                FitMode::Contain if w >= x_dim && h >= y_dim => img,
+                FitMode::Contain => img.resize(w, h, filter),
+                fit => {
+                    // Cover scales by the larger ratio, then crops
+                    let scale = (w as f64 / x_dim as f64).max(h as f64 / y_dim as f64);
+                    let scaled_pixels = match fit {
+                        FitMode::Cover => {
+                            (x_dim as f64 * scale).ceil() * (y_dim as f64 * scale).ceil()
+                        }
+                        _ => w as f64 * h as f64,
+                    };
+                    if w > x_dim.saturating_mul(MAX_TRANSFORM_UPSCALE)
+                        || h > y_dim.saturating_mul(MAX_TRANSFORM_UPSCALE)
+                        || scaled_pixels > limits.max_pixels as f64
+                    {
+                        warn!(
+                            "Transform enlarges image too much, id={}, x={}, y={}, width={}, height={}, fit={:?}",
+                            self.id, x_dim, y_dim, w, h, fit
+                        );
+                        return Err(Errors::InvalidTransform);
+                    }
+                    if fit == FitMode::Cover {
+                        img.resize_to_fill(w, h, filter)
+                    } else {
+                        img.resize_exact(w, h, filter)
+                    }
+                }
+            },
+            (Some(w), None) if w < x_dim => img.resize(w, u32::MAX, filter),
+            (None, Some(h)) if h < y_dim => img.resize(u32::MAX, h, filter),
+            _ => img,
+        };
+
+        let format = match transform.format {
+            Some(format) if format != OutputFormat::Auto => format,
+            _ if image::guess_format(&self.bytes).ok() == Some(ImageFormat::Jpeg) => {
+                OutputFormat::Jpeg
+            }
+            _ => OutputFormat::Png,
+        };
+        info!(
+            "Image transform, id={}, x={}, y={}, new_x={}, new_y={}, format={:?}, quality={:?}",
+            self.id,
+            x_dim,
+            y_dim,
+            img.width(),
+            img.height(),
+            format,
+            transform.quality
+        );
+        self.encode_image(&img, format, transform.quality)
+    }
+
+    /// Reduces the image to a few pixels of detail, then scales it back up
+    /// with the given filter
+    fn placeholder_image(
+        &self,
+        filter: FilterType,
+        limits: &ImageLimits,
+    ) -> Result<DynamicImage, Errors> {
+        let img = self.load_image(limits)?;
+        let (x_dim, y_dim) = img.dimensions();
+        let scale = (PLACEHOLDER_DIMENSION as f32 / x_dim.max(y_dim) as f32).min(1_f32);
+        let width = ((x_dim as f32 * scale).round() as u32).max(1);
+        let height = ((y_dim as f32 * scale).round() as u32).max(1);
+        Ok(img
+            .thumbnail(PLACEHOLDER_DETAIL, PLACEHOLDER_DETAIL)
+            .resize_exact(width, height, filter))
+    }
+
+    /// Renders a heavily blurred JPEG of the image for use in place of it
+    pub fn blurred(&self, limits: &ImageLimits) -> Result<Document, Errors> {
+        let img = self.placeholder_image(FilterType::Triangle, limits)?;
+        let sigma = img.width().max(img.height()) as f32 / PLACEHOLDER_DETAIL as f32;
+        self.encode_image(&img.blur(sigma), OutputFormat::Jpeg, None)
+    }
+
+    /// Renders a coarsely pixelated PNG of the image for use in place of it
+    pub fn pixelated(&self, limits: &ImageLimits) -> Result<Document, Errors> {
+        let img = self.placeholder_image(FilterType::Nearest, limits)?;
+        self.encode_image(&img, OutputFormat::Png, None)
+    }
+
+    /// Computes a BlurHash of the image with 4x3 components
+    pub fn blurhash(&self, limits: &ImageLimits) -> Result<String, Errors> {
+        let img = self.load_image(limits)?.thumbnail(32, 32).to_rgba8();
+        Ok(blurhash::encode(&img, 4, 3))
+    }
+
+    /// Splits an animated image into PNG documents of its sampled frames,
+    /// or a single PNG contact sheet of them, along with the number of
+    /// frames. Returns `None` if the image is not animated.
+    pub fn frame_documents(
+        &self,
+        max_frames: usize,
+        contact_sheet: bool,
+        limits: &ImageLimits,
+    ) -> Result<Option<(Vec<Document>, u32)>, Errors> {
+        let (frames, frame_count) = match self.sample_frames(max_frames, limits)? {
+            Some(sampled) => sampled,
+            None => return Ok(None),
+        };
+        let documents = if contact_sheet {
+            vec![self.image_document(Self::contact_sheet(&frames))?]
+        } else {
+            frames
+                .into_iter()
+                .map(|frame| self.image_document(frame))
+                .collect::<Result<Vec<Document>, Errors>>()?
+        };
+        Ok(Some((documents, frame_count)))
+    }
+
+    /// Whether the leading `ftyp` box lists an AVIF brand, either as the
+    /// major brand or as one of the compatible brands, as files with a
+    /// generic major brand such as `mif1` do
+    fn is_avif(bytes: &[u8]) -> bool {
+        if bytes.len() < 12 || &bytes[4..8] != b"ftyp" {
+            return false;
+        }
+        let size = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+        let compatible = bytes.get(16..size.min(bytes.len())).unwrap_or_default();
+        std::iter::once(&bytes[8..12])
+            .chain(compatible.chunks_exact(4))
+            .any(|brand| brand == b"avif" || brand == b"avis")
+    }
+
+    /// Detects the image type from the leading bytes of the document,
+    /// ignoring whatever type the origin claimed
+    pub fn detect_content_type(&self) -> Option<&'static str> {
+        let bytes = self.bytes.as_ref();
+        if Self::is_avif(bytes) {
+            return Some("image/avif");
+        }
+        match image::guess_format(bytes) {
+            // Any RIFF container is reported as WebP
+            Ok(ImageFormat::WebP) if bytes.len() < 12 || &bytes[8..12] != b"WEBP" => None,
+            Ok(format) => Some(format.to_mime_type()),
+            Err(_) if Self::looks_like_svg(bytes) => Some("image/svg+xml"),
+            Err(_) => None,
+        }
+    }
+
+    fn looks_like_svg(bytes: &[u8]) -> bool {
+        let head = &bytes[..bytes.len().min(SVG_SNIFF_LENGTH)];
+        let head = String::from_utf8_lossy(head).to_ascii_lowercase();
+        let head = head.trim_start_matches('\u{feff}').trim_start();
+        head.starts_with('<') && head.contains("<svg") && !head.contains("<html")
+    }
+
+    pub fn is_svg(&self) -> bool {
+        SupportedMimeTypes::from_string(&self.content_type) == SupportedMimeTypes::ImageSvg
+    }
+
+    /// Returns a copy of the SVG with scripts, event handlers and external
+    /// references removed
+    pub fn sanitize_svg(&self) -> Result<Document, Errors> {
+        let bytes = svg::sanitize(&self.bytes).map_err(|e| {
+            error!("Unable to sanitize svg, id={}, reason={}", self.id, e);
+            Errors::InvalidSvg
+        })?;
+        info!(
+            "Svg sanitized, id={}, len={}, new_len={}",
+            self.id,
+            self.bytes.len(),
+            bytes.len()
+        );
+        Ok(Document {
+            id: self.id,
+            content_length: bytes.len() as u64,
+            content_type: self.content_type.clone(),
+            bytes: Bytes::from(bytes),
+            url: self.url.clone(),
+        })
+    }
+
+    /// Removes EXIF, XMP and textual metadata, keeping ICC profiles and the
+    /// orientation. Returns `None` if the format carries no such metadata
+    /// or the image could not be parsed.
+    pub fn strip_metadata(&self) -> Option<Document> {
+        let bytes = match metadata::strip(&self.bytes)? {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                warn!("Unable to strip metadata, id={}, reason={}", self.id, e);
+                metrics::DOCUMENT
+                    .with_label_values(&["metadata_strip_failed"])
+                    .inc();
+                return None;
+            }
+        };
+        info!(
+            "Metadata stripped, id={}, len={}, new_len={}",
+            self.id,
+            self.bytes.len(),
+            bytes.len()
+        );
+        metrics::DOCUMENT
+            .with_label_values(&["metadata_stripped"])
+            .inc();
+        Some(Document {
+            id: self.id,
+            content_length: bytes.len() as u64,
+            content_type: self.content_type.clone(),
+            bytes: Bytes::from(bytes),
+            url: self.url.clone(),
+        })
+    }
+
+    /// Renders the SVG into a PNG document
+    pub fn rasterize_svg(&self, limits: &ImageLimits) -> Result<Document, Errors> {
+        let img = svg::rasterize(&self.bytes, NOMINAL_IMAGE_DIMENSION, limits).map_err(|e| {
+            error!("Unable to rasterize svg, id={}, reason={}", self.id, e);
+            Errors::InvalidSvg
+        })?;
+        self.image_document(img)
+    }
+
     pub fn to_url(&self) -> String {
         format!(
             "data:{};base64,{}",
@@ -165,7 +1029,9 @@ impl Document {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use image::{GenericImage, Rgba};
+    use crate::utils::hamming_distance;
+    use image::codecs::gif::GifEncoder;
+    use image::{Delay, Frame, GenericImage, Rgba};
     use rand::Rng;
 
     const X_SIZE: u32 = 1600;
@@ -206,6 +1072,401 @@ mod tests {
         }
     }
 
+    fn construct_gradient(x_dim: u32, y_dim: u32, reverse: bool) -> DynamicImage {
+        let mut new_image = DynamicImage::new_rgba8(x_dim, y_dim);
+        for x in 0..x_dim {
+            for y in 0..y_dim {
+                let value = ((x + y) * 255 / (x_dim + y_dim)) as u8;
+                let value = if reverse { 255 - value } else { value };
+                new_image.put_pixel(x, y, Rgba([value, value / 2, 255 - value, 255]));
+            }
+        }
+        new_image
+    }
+
+    #[test]
+    fn test_perceptual_hash() {
+        let image = construct_gradient(320, 240, false);
+        let mut png = Cursor::new(Vec::new());
+        image.write_to(&mut png, ImageOutputFormat::Png).unwrap();
+        let mut jpeg = Cursor::new(Vec::new());
+        image
+            .resize(160, 120, FilterType::Triangle)
+            .write_to(&mut jpeg, ImageOutputFormat::Jpeg(60))
+            .unwrap();
+
+        let original = construct_document(png.get_ref())
+            .perceptual_hash(&ImageLimits::default())
+            .unwrap();
+        let reencoded = construct_document(jpeg.get_ref())
+            .perceptual_hash(&ImageLimits::default())
+            .unwrap();
+        assert!(hamming_distance(original, reencoded) <= 4);
+
+        let reversed = Document::difference_hash(&construct_gradient(320, 240, true));
+        assert!(hamming_distance(original, reversed) > 32);
+
+        let invalid =
+            construct_document("hello world".as_bytes()).perceptual_hash(&ImageLimits::default());
+        assert!(invalid.is_err());
+    }
+
+    fn construct_animation(frame_count: u8) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        {
+            let mut encoder = GifEncoder::new(&mut bytes);
+            let frames = (0..frame_count).map(|i| {
+                let buffer = RgbaImage::from_pixel(40, 30, Rgba([i * 10, 0, 0, 255]));
+                Frame::from_parts(buffer, 0, 0, Delay::from_numer_denom_ms(100, 1))
+            });
+            encoder.encode_frames(frames).unwrap();
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_sample_frames() {
+        let document = construct_document(&construct_animation(10));
+        let (frames, frame_count) = document
+            .sample_frames(4, &ImageLimits::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!((frames.len(), frame_count), (3, 10));
+        // Frames 0, 4 and 8 are sampled
+        let reds: Vec<u8> = frames.iter().map(|f| f.get_pixel(0, 0)[0]).collect();
+        assert_eq!(reds, vec![0, 40, 80]);
+
+        let (frames, _) = document
+            .sample_frames(20, &ImageLimits::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(frames.len(), 10);
+
+        // Still images are not sampled
+        let still = construct_document(&construct_image(40, 30));
+        assert!(still
+            .sample_frames(4, &ImageLimits::default())
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_image_info() {
+        let limits = ImageLimits::default();
+        let mut img = RgbaImage::from_pixel(40, 30, Rgba([200, 30, 30, 255]));
+        for x in 0..10 {
+            img.put_pixel(x, 0, Rgba([0, 0, 255, 255]));
+        }
+        let mut bytes = Cursor::new(Vec::new());
+        img.write_to(&mut bytes, ImageOutputFormat::Png).unwrap();
+        let bytes = bytes.into_inner();
+        let document = construct_document(&bytes);
+        let decoded = document.load_image(&limits).unwrap();
+        let info = document.image_info(&decoded, None, &limits).unwrap();
+        assert_eq!(
+            info,
+            ImageInfo {
+                width: 40,
+                height: 30,
+                format: String::from("png"),
+                size: bytes.len() as u64,
+                frames: 1,
+                dominant_color: String::from("#c81e1e"),
+            }
+        );
+
+        let animation = construct_document(&construct_animation(10));
+        let decoded = animation.load_image(&limits).unwrap();
+        let info = animation.image_info(&decoded, None, &limits).unwrap();
+        assert_eq!((info.format.as_str(), info.frames), ("gif", 10));
+        // A frame count known from sampling is not counted again
+        let info = animation.image_info(&decoded, Some(12), &limits).unwrap();
+        assert_eq!(info.frames, 12);
+
+        assert!(construct_document(b"hello world")
+            .image_info(&decoded, None, &limits)
+            .is_err());
+    }
+
+    #[test]
+    fn test_frame_documents() {
+        let document = construct_document(&construct_animation(5));
+        let (documents, frame_count) = document
+            .frame_documents(5, false, &ImageLimits::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!((documents.len(), frame_count), (5, 5));
+        assert!(documents.iter().all(|d| d.content_type == "image/png"));
+
+        let (documents, _) = document
+            .frame_documents(5, true, &ImageLimits::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(documents.len(), 1);
+        let sheet = documents[0].load_image(&ImageLimits::default()).unwrap();
+        // 5 frames tile into a 3x2 grid
+        assert_eq!(sheet.dimensions(), (120, 60));
+    }
+
+    #[test]
+    fn test_webp() {
+        let image = construct_gradient(200, 100, false);
+        let mut webp = Cursor::new(Vec::new());
+        image.write_to(&mut webp, ImageOutputFormat::WebP).unwrap();
+        let mut document = construct_document(webp.get_ref());
+        document.content_type = "image/webp".to_string();
+
+        assert_eq!(
+            document
+                .load_image(&ImageLimits::default())
+                .unwrap()
+                .dimensions(),
+            (200, 100)
+        );
+        assert!(document
+            .sample_frames(4, &ImageLimits::default())
+            .unwrap()
+            .is_none());
+
+        // Converted for providers that only accept png
+        let converted = document
+            .resize_image(
+                1024_u64 * 1024_u64,
+                false,
+                &ResizeConfig::default(),
+                &ImageLimits::default(),
+            )
+            .unwrap();
+        assert_eq!(converted.content_type, "image/png");
+        assert_eq!(
+            image::guess_format(&converted.bytes).unwrap(),
+            ImageFormat::Png
+        );
+    }
+
+    #[test]
+    fn test_detect_content_type() {
+        let png = construct_document(&construct_image(8, 8));
+        assert_eq!(png.detect_content_type(), Some("image/png"));
+
+        let mut gif = construct_document(&construct_animation(2));
+        gif.content_type = "text/plain".to_string();
+        assert_eq!(gif.detect_content_type(), Some("image/gif"));
+
+        let svg = construct_document(
+            b"<?xml version=\"1.0\"?>\n<svg xmlns=\"http://www.w3.org/2000/svg\"/>",
+        );
+        assert_eq!(svg.detect_content_type(), Some("image/svg+xml"));
+
+        let avif = construct_document(b"\0\0\0\x18ftypavif\0\0\0\0");
+        assert_eq!(avif.detect_content_type(), Some("image/avif"));
+
+        // Generic major brands list AVIF among the compatible brands
+        let avif = construct_document(b"\0\0\0\x1cftypmif1\0\0\0\0mif1miafavif");
+        assert_eq!(avif.detect_content_type(), Some("image/avif"));
+
+        let heic = construct_document(b"\0\0\0\x18ftypmif1\0\0\0\0heic");
+        assert_eq!(heic.detect_content_type(), None);
+
+        let wav = construct_document(b"RIFF\0\0\0\0WAVEfmt ");
+        assert_eq!(wav.detect_content_type(), None);
+
+        let html = construct_document(b"<!DOCTYPE html><html><body><svg/></body></html>");
+        assert_eq!(html.detect_content_type(), None);
+    }
+
+    #[test]
+    fn test_image_limits() {
+        let document = construct_document(&construct_image(300, 200));
+        let limits = ImageLimits::default();
+        assert!(document.check_limits(&limits).is_ok());
+
+        let width = ImageLimits {
+            max_width: 299,
+            ..ImageLimits::default()
+        };
+        assert_eq!(document.check_limits(&width), Err(Errors::ImageTooLarge));
+        assert_eq!(document.perceptual_hash(&width), Err(Errors::ImageTooLarge));
+
+        let pixels = ImageLimits {
+            max_pixels: 300 * 200 - 1,
+            ..ImageLimits::default()
+        };
+        assert_eq!(
+            document.load_image(&pixels).err(),
+            Some(Errors::ImageTooLarge)
+        );
+
+        // The header is within limits but decoding needs more memory
+        let alloc = ImageLimits {
+            max_alloc: 1024,
+            ..ImageLimits::default()
+        };
+        assert!(document.check_limits(&alloc).is_ok());
+        assert_eq!(
+            document.load_image(&alloc).err(),
+            Some(Errors::ImageTooLarge)
+        );
+
+        let animation = construct_document(&construct_animation(3));
+        let height = ImageLimits {
+            max_height: 29,
+            ..ImageLimits::default()
+        };
+        assert_eq!(
+            animation.sample_frames(3, &height).err(),
+            Some(Errors::ImageTooLarge)
+        );
+        assert_eq!(
+            animation.sample_frames(3, &alloc).err(),
+            Some(Errors::ImageTooLarge)
+        );
+
+        let frames = ImageLimits {
+            max_frames: 2,
+            ..ImageLimits::default()
+        };
+        assert_eq!(
+            animation.sample_frames(3, &frames).err(),
+            Some(Errors::ImageTooLarge)
+        );
+    }
+
+    #[test]
+    fn test_transform() {
+        let document = construct_document(&construct_image(400, 200));
+        let limits = ImageLimits::default();
+        let dimensions = |d: Document| d.load_image(&limits).unwrap().dimensions();
+        let resize = |width, height, fit| ImageTransform {
+            width,
+            height,
+            fit,
+            ..Default::default()
+        };
+
+        let result = document
+            .transform(&resize(Some(100), None, FitMode::Contain), &limits)
+            .unwrap();
+        assert_eq!(result.content_type, "image/png");
+        assert_eq!(dimensions(result), (100, 50));
+
+        let result = document
+            .transform(&resize(Some(100), Some(100), FitMode::Contain), &limits)
+            .unwrap();
+        assert_eq!(dimensions(result), (100, 50));
+
+        let result = document
+            .transform(&resize(Some(100), Some(100), FitMode::Cover), &limits)
+            .unwrap();
+        assert_eq!(dimensions(result), (100, 100));
+
+        let result = document
+            .transform(&resize(Some(800), Some(100), FitMode::Fill), &limits)
+            .unwrap();
+        assert_eq!(dimensions(result), (800, 100));
+
+        // Enlarging beyond the upscale limit or the pixel limit is rejected
+        let result = document.transform(&resize(Some(1601), Some(100), FitMode::Fill), &limits);
+        assert_eq!(result.err(), Some(Errors::InvalidTransform));
+        let result = document.transform(&resize(Some(10), Some(801), FitMode::Cover), &limits);
+        assert_eq!(result.err(), Some(Errors::InvalidTransform));
+        let small_limits = ImageLimits {
+            max_pixels: 100_000,
+            ..Default::default()
+        };
+        let result =
+            document.transform(&resize(Some(800), Some(400), FitMode::Fill), &small_limits);
+        assert_eq!(result.err(), Some(Errors::InvalidTransform));
+
+        // Not enlarged when only fitting within a box
+        let result = document
+            .transform(&resize(None, Some(400), FitMode::Contain), &limits)
+            .unwrap();
+        assert_eq!(dimensions(result), (400, 200));
+
+        let transform = ImageTransform {
+            format: Some(OutputFormat::Jpeg),
+            ..Default::default()
+        };
+        let result = document.transform(&transform, &limits).unwrap();
+        assert_eq!(result.content_type, "image/jpeg");
+        assert_eq!(
+            image::guess_format(&result.bytes).unwrap(),
+            ImageFormat::Jpeg
+        );
+
+        let transform = ImageTransform {
+            width: Some(40),
+            format: Some(OutputFormat::Webp),
+            ..Default::default()
+        };
+        let result = document.transform(&transform, &limits).unwrap();
+        assert_eq!(result.content_type, "image/webp");
+        assert_eq!(dimensions(result), (40, 20));
+    }
+
+    #[test]
+    fn test_placeholders() {
+        let document = construct_document(&construct_image(400, 200));
+        let limits = ImageLimits::default();
+
+        let blurred = document.blurred(&limits).unwrap();
+        assert_eq!(blurred.content_type, "image/jpeg");
+        let img = blurred.load_image(&limits).unwrap();
+        assert_eq!(img.dimensions(), (256, 128));
+
+        let pixelated = document.pixelated(&limits).unwrap();
+        assert_eq!(pixelated.content_type, "image/png");
+        let img = pixelated.load_image(&limits).unwrap().to_rgba8();
+        assert_eq!(img.dimensions(), (256, 128));
+        // Each of the 16x8 source pixels becomes a 16x16 block
+        assert_eq!(img.get_pixel(0, 0), img.get_pixel(15, 15));
+        assert_eq!(img.get_pixel(16, 16), img.get_pixel(31, 31));
+
+        let small = construct_document(&construct_image(20, 10));
+        let img = small
+            .pixelated(&limits)
+            .unwrap()
+            .load_image(&limits)
+            .unwrap();
+        assert_eq!(img.dimensions(), (20, 10));
+
+        assert_eq!(document.blurhash(&limits).unwrap().len(), 28);
+    }
+
+    #[test]
+    fn test_transform_quality() {
+        let document = construct_document(&construct_image(400, 200));
+        let limits = ImageLimits::default();
+        let encoded_length = |format, quality| {
+            let transform = ImageTransform {
+                format: Some(format),
+                quality: Some(quality),
+                ..Default::default()
+            };
+            document.transform(&transform, &limits).unwrap().bytes.len()
+        };
+
+        assert!(encoded_length(OutputFormat::Jpeg, 10) < encoded_length(OutputFormat::Jpeg, 100));
+        assert!(encoded_length(OutputFormat::Webp, 10) < encoded_length(OutputFormat::Webp, 100));
+
+        // Unresolved automatic formats keep the source format
+        let transform = ImageTransform {
+            format: Some(OutputFormat::Auto),
+            ..Default::default()
+        };
+        let result = document.transform(&transform, &limits).unwrap();
+        assert_eq!(result.content_type, "image/png");
+
+        // Transparent pixels become white rather than black in a jpeg
+        let transparent = DynamicImage::ImageRgba8(RgbaImage::new(16, 16));
+        let result = document
+            .encode_image(&transparent, OutputFormat::Jpeg, None)
+            .unwrap();
+        let decoded = image::load_from_memory(&result.bytes).unwrap().to_rgb8();
+        assert!(decoded.pixels().all(|p| p.0.iter().all(|c| *c > 240)));
+    }
+
     #[test]
     fn test_to_url() {
         let bytes = "hello world".as_bytes();
@@ -270,7 +1531,7 @@ mod tests {
         let document = construct_document(image_bytes.as_slice());
 
         // Check if image can be loaded
-        let loaded_image = document.load_image();
+        let loaded_image = document.load_image(&ImageLimits::default());
         assert!(loaded_image.is_ok());
         let dimensions = loaded_image.unwrap().dimensions();
         assert_eq!(dimensions, (X_SIZE, Y_SIZE));
@@ -279,14 +1540,48 @@ mod tests {
         let max_size_5mb = 1024_u64 * 1024_u64 * 5_u64;
 
         // Resize required
-        let new_document = document.resize_image(max_size_5mb);
+        let new_document = document.resize_image(
+            max_size_5mb,
+            false,
+            &ResizeConfig::default(),
+            &ImageLimits::default(),
+        );
         assert!(new_document.is_ok());
         let new_document = new_document.unwrap();
         assert!(new_document.bytes.len() < document.bytes.len());
-        let loaded_image = new_document.load_image();
+        let loaded_image = new_document.load_image(&ImageLimits::default());
         assert!(loaded_image.is_ok());
         let dimensions = loaded_image.unwrap().dimensions();
         //TODO: Recheck why after img.resize is the y dimension of the image is off by -1
         assert_eq!(dimensions.0, NOMINAL_IMAGE_DIMENSION);
     }
+
+    #[test]
+    fn test_resize_image_limits() {
+        let document = construct_document(&construct_image(X_SIZE, Y_SIZE));
+        let limits = ImageLimits::default();
+        let max_size = 100_u64 * 1024_u64;
+
+        let png = document
+            .resize_image(max_size, false, &ResizeConfig::default(), &limits)
+            .unwrap();
+        assert_eq!(png.content_type, "image/png");
+        assert!(png.bytes.len() as u64 <= max_size);
+
+        let config = ResizeConfig {
+            filter: ResizeFilter::Lanczos3,
+            ..Default::default()
+        };
+        let jpeg = document
+            .resize_image(max_size, true, &config, &limits)
+            .unwrap();
+        assert_eq!(jpeg.content_type, "image/jpeg");
+        assert!(jpeg.bytes.len() as u64 <= max_size);
+        // JPEG keeps more of the image within the same size
+        let jpeg_width = jpeg.load_image(&limits).unwrap().width();
+        assert!(jpeg_width >= png.load_image(&limits).unwrap().width());
+        assert!(jpeg_width <= NOMINAL_IMAGE_DIMENSION);
+
+        assert_eq!(ResizeConfig::default().jpeg_qualities(), vec![85, 75, 65]);
+    }
 }
@@ -0,0 +1,270 @@
+use std::collections::HashMap;
+use std::fs;
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    document::{Document, ImageLimits},
+    utils::{hamming_distance, md5, sha256},
+};
+
+type GenericError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Deserialize, Clone)]
+pub struct HashListConfig {
+    /// Path to the hash list file
+    pub path: String,
+    /// Maximum hamming distance for perceptual hash entries to match
+    pub max_distance: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum HashListMatchType {
+    Sha256,
+    Md5,
+    Perceptual,
+}
+
+impl std::fmt::Display for HashListMatchType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashListMatch {
+    pub match_type: HashListMatchType,
+    /// The hash list entry that matched, as hex
+    pub entry: String,
+    /// Optional reference supplied with the entry by the list provider
+    pub reference: Option<String>,
+    pub distance: u32,
+}
+
+/// A list of hashes of known illegal imagery as supplied by a clearinghouse.
+///
+/// The list file holds one entry per line in the form
+/// `<algorithm>,<hex hash>[,<reference>]` where algorithm is one of `sha256`,
+/// `md5` or `phash`. Perceptual entries are 64 bit difference hashes as
+/// computed by `Document::perceptual_hash`. Empty lines and lines starting
+/// with `#` are ignored.
+pub struct HashList {
+    sha256: HashMap<String, Option<String>>,
+    md5: HashMap<String, Option<String>>,
+    perceptual: Vec<(u64, Option<String>)>,
+    max_distance: u32,
+}
+
+impl HashList {
+    pub fn load(config: &HashListConfig) -> Result<HashList, GenericError> {
+        info!("Loading hash list from `{}`", config.path);
+        let contents = fs::read_to_string(&config.path)?;
+        let hash_list = HashList::parse(&contents, config.max_distance)?;
+        info!(
+            "Hash list loaded, sha256={}, md5={}, perceptual={}",
+            hash_list.sha256.len(),
+            hash_list.md5.len(),
+            hash_list.perceptual.len()
+        );
+        Ok(hash_list)
+    }
+
+    pub fn parse(contents: &str, max_distance: u32) -> Result<HashList, GenericError> {
+        let mut hash_list = HashList {
+            sha256: HashMap::new(),
+            md5: HashMap::new(),
+            perceptual: Vec::new(),
+            max_distance,
+        };
+
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.splitn(3, ',').map(|f| f.trim());
+            let algorithm = fields.next().unwrap_or_default().to_ascii_lowercase();
+            let hash = fields.next().unwrap_or_default().to_ascii_lowercase();
+            let reference = fields
+                .next()
+                .filter(|r| !r.is_empty())
+                .map(|r| r.to_string());
+
+            if hash.is_empty() || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(format!("Invalid hash on line {} of hash list", index + 1).into());
+            }
+            match (algorithm.as_str(), hash.len()) {
+                ("sha256", 64) => {
+                    hash_list.sha256.insert(hash, reference);
+                }
+                ("md5", 32) => {
+                    hash_list.md5.insert(hash, reference);
+                }
+                ("phash", 16) => {
+                    let hash = u64::from_str_radix(&hash, 16)?;
+                    hash_list.perceptual.push((hash, reference));
+                }
+                _ => {
+                    return Err(format!(
+                        "Unsupported algorithm or hash length on line {} of hash list",
+                        index + 1
+                    )
+                    .into())
+                }
+            }
+        }
+        Ok(hash_list)
+    }
+
+    pub fn len(&self) -> usize {
+        self.sha256.len() + self.md5.len() + self.perceptual.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn exact_match(
+        match_type: HashListMatchType,
+        entries: &HashMap<String, Option<String>>,
+        hash: String,
+    ) -> Option<HashListMatch> {
+        entries.get(&hash).map(|reference| HashListMatch {
+            match_type,
+            entry: hash.clone(),
+            reference: reference.clone(),
+            distance: 0,
+        })
+    }
+
+    /// Checks the document against the list. Exact hashes are checked first,
+    /// the image is only decoded if the list contains perceptual entries.
+    /// SVGs are sanitized before they are rendered for the perceptual hash.
+    pub fn check(&self, document: &Document, limits: &ImageLimits) -> Option<HashListMatch> {
+        if !self.sha256.is_empty() {
+            let hash = sha256(&document.bytes);
+            if let Some(m) = Self::exact_match(HashListMatchType::Sha256, &self.sha256, hash) {
+                return Some(m);
+            }
+        }
+
+        if !self.md5.is_empty() {
+            let hash = md5(&document.bytes);
+            if let Some(m) = Self::exact_match(HashListMatchType::Md5, &self.md5, hash) {
+                return Some(m);
+            }
+        }
+
+        if self.perceptual.is_empty() {
+            return None;
+        }
+        let hash = if document.is_svg() {
+            document
+                .sanitize_svg()
+                .and_then(|sanitized| sanitized.perceptual_hash(limits))
+        } else {
+            document.perceptual_hash(limits)
+        };
+        match hash {
+            Ok(hash) => self
+                .perceptual
+                .iter()
+                .map(|(entry, reference)| (entry, reference, hamming_distance(*entry, hash)))
+                .filter(|(_, _, distance)| *distance <= self.max_distance)
+                .min_by_key(|(_, _, distance)| *distance)
+                .map(|(entry, reference, distance)| HashListMatch {
+                    match_type: HashListMatchType::Perceptual,
+                    entry: format!("{:016x}", entry),
+                    reference: reference.clone(),
+                    distance,
+                }),
+            Err(e) => {
+                warn!(
+                    "Unable to compute perceptual hash for hash list, id={}, reason={:?}",
+                    document.id, e
+                );
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::body::Bytes;
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn construct_document(bytes: &[u8]) -> Document {
+        Document {
+            id: Uuid::new_v4(),
+            content_type: "image/png".to_string(),
+            content_length: bytes.len() as u64,
+            bytes: Bytes::copy_from_slice(bytes),
+            url: "http://localhost/image.png".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let contents = format!(
+            "# comment\n\nsha256,{}\nMD5,{},ref-1\nphash,00000000000000ff\n",
+            sha256(b"bad"),
+            md5(b"worse").to_ascii_uppercase()
+        );
+        let hash_list = HashList::parse(&contents, 2).unwrap();
+        assert_eq!(hash_list.len(), 3);
+        assert_eq!(
+            hash_list.md5.get(&md5(b"worse")),
+            Some(&Some("ref-1".to_string()))
+        );
+
+        assert!(HashList::parse("sha1,abcd", 0).is_err());
+        assert!(HashList::parse("md5,not_hex", 0).is_err());
+        assert!(HashList::parse("phash,abcd", 0).is_err());
+    }
+
+    #[test]
+    fn test_check_exact() {
+        let contents = format!("sha256,{}\nmd5,{},ref-1\n", sha256(b"bad"), md5(b"worse"));
+        let hash_list = HashList::parse(&contents, 0).unwrap();
+
+        let result = hash_list
+            .check(&construct_document(b"bad"), &ImageLimits::default())
+            .unwrap();
+        assert_eq!(result.match_type, HashListMatchType::Sha256);
+        assert_eq!(result.reference, None);
+
+        let result = hash_list
+            .check(&construct_document(b"worse"), &ImageLimits::default())
+            .unwrap();
+        assert_eq!(result.match_type, HashListMatchType::Md5);
+        assert_eq!(result.entry, md5(b"worse"));
+        assert_eq!(result.reference, Some("ref-1".to_string()));
+
+        assert!(hash_list
+            .check(&construct_document(b"fine"), &ImageLimits::default())
+            .is_none());
+    }
+
+    #[test]
+    fn test_check_svg() {
+        let svg = br#"<svg xmlns="http://www.w3.org/2000/svg" width="16" height="16"><script>alert(1)</script><rect width="8" height="16" fill="red"/></svg>"#;
+        let document = Document {
+            content_type: "image/svg+xml".to_string(),
+            ..construct_document(svg)
+        };
+        let hash = document
+            .sanitize_svg()
+            .unwrap()
+            .perceptual_hash(&ImageLimits::default())
+            .unwrap();
+        let hash_list = HashList::parse(&format!("phash,{:016x}", hash), 0).unwrap();
+
+        let result = hash_list.check(&document, &ImageLimits::default()).unwrap();
+        assert_eq!(result.match_type, HashListMatchType::Perceptual);
+        assert_eq!(result.distance, 0);
+    }
+}
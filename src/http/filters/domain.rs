@@ -0,0 +1,233 @@
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use hyper::Uri;
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+
+use crate::config::Configuration;
+use crate::utils::{percent_decode, remove_dot_segments};
+
+use super::{FilterAction, UriFilter};
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct DomainRule {
+    /// `example.com` matches that host only, `.example.com` the domain and
+    /// all of its subdomains, and `*` matches any run of characters, e.g.
+    /// `img-*.example.com`
+    pub host: String,
+    /// Optional prefix the url path must start with, matched against the
+    /// percent-decoded path without dot segments
+    pub path_prefix: Option<String>,
+    pub action: FilterAction,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct DomainFilterConfig {
+    /// Rules are checked in order, the first matching rule decides
+    pub rules: Vec<DomainRule>,
+    /// Action taken for urls no rule matches
+    pub default_action: FilterAction,
+    /// Seconds between reloads of the rules from proxy.conf, never reloaded
+    /// if omitted or 0
+    pub reload_interval: Option<u64>,
+}
+
+/// Allows or denies urls by host and path using ordered rules. Clones share
+/// the same rules, so a clone can reload them while another is filtering.
+#[derive(Clone)]
+pub struct DomainFilter {
+    config: Arc<RwLock<DomainFilterConfig>>,
+}
+
+impl DomainFilter {
+    pub fn new(config: &DomainFilterConfig) -> DomainFilter {
+        DomainFilter {
+            config: Arc::new(RwLock::new(config.clone())),
+        }
+    }
+
+    /// Replaces the rules, returns whether they changed
+    pub fn reload(&self, config: &DomainFilterConfig) -> bool {
+        let mut current = self.config.write().unwrap();
+        if *current == *config {
+            return false;
+        }
+        *current = config.clone();
+        true
+    }
+
+    /// Periodically reloads the rules from proxy.conf, keeping the current
+    /// rules if the file cannot be loaded. A zero interval disables reloads.
+    pub fn watch(&self, interval: Duration) {
+        if interval.is_zero() {
+            warn!("Domain filter reload interval is zero, rules are not reloaded");
+            return;
+        }
+        let filter = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                match tokio::task::spawn_blocking(Configuration::load).await {
+                    Ok(Ok(Configuration {
+                        domain_filter: Some(config),
+                        ..
+                    })) => {
+                        if filter.reload(&config) {
+                            info!("Domain filter rules reloaded, rules={}", config.rules.len());
+                        }
+                    }
+                    Ok(Ok(_)) => warn!("Domain filter missing from configuration, keeping rules"),
+                    Ok(Err(e)) => error!("Unable to reload domain filter, reason={}", e),
+                    Err(e) => error!("Unable to reload domain filter, reason={}", e),
+                }
+            }
+        });
+    }
+}
+
+/// Matches `host` against a rule pattern, both already lowercased
+fn host_matches(pattern: &str, host: &str) -> bool {
+    if let Some(domain) = pattern.strip_prefix('.') {
+        return host == domain || host.ends_with(pattern);
+    }
+    if !pattern.contains('*') {
+        return host == pattern;
+    }
+
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match host.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    let last = parts.pop().unwrap_or_default();
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+#[async_trait]
+impl UriFilter for DomainFilter {
+    async fn filter(&self, uri: &Uri) -> bool {
+        let host = match uri.host() {
+            Some(host) => host.trim_end_matches('.').to_lowercase(),
+            None => {
+                warn!("No host specified in request");
+                return false;
+            }
+        };
+        let path = remove_dot_segments(&percent_decode(uri.path()));
+        let config = self.config.read().unwrap();
+        let action = config
+            .rules
+            .iter()
+            .find(|rule| {
+                host_matches(&rule.host.to_lowercase(), &host)
+                    && match &rule.path_prefix {
+                        Some(prefix) => path.starts_with(prefix.as_str()),
+                        None => true,
+                    }
+            })
+            .map_or(&config.default_action, |rule| &rule.action);
+        debug!("Domain filter, host:{}, action:{:?}", host, action);
+        *action == FilterAction::Allow
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(host: &str, path_prefix: Option<&str>, action: FilterAction) -> DomainRule {
+        DomainRule {
+            host: host.to_string(),
+            path_prefix: path_prefix.map(String::from),
+            action,
+        }
+    }
+
+    #[test]
+    fn test_host_matches() {
+        assert!(host_matches("example.com", "example.com"));
+        assert!(!host_matches("example.com", "www.example.com"));
+        assert!(host_matches(".example.com", "example.com"));
+        assert!(host_matches(".example.com", "a.b.example.com"));
+        assert!(!host_matches(".example.com", "badexample.com"));
+        assert!(host_matches("img-*.example.com", "img-01.example.com"));
+        assert!(!host_matches("img-*.example.com", "cdn.example.com"));
+        assert!(host_matches("*.cdn.*", "eu.cdn.example.com"));
+        assert!(!host_matches("a*a", "a"));
+        assert!(host_matches("*", "anything.com"));
+    }
+
+    #[tokio::test]
+    async fn test_path_normalization() {
+        let config = DomainFilterConfig {
+            rules: vec![
+                rule(".example.com", Some("/private/"), FilterAction::Deny),
+                rule(".example.com", Some("/nft/"), FilterAction::Allow),
+            ],
+            default_action: FilterAction::Allow,
+            reload_interval: None,
+        };
+        let filter = DomainFilter::new(&config);
+        let allowed = |url: &str| {
+            let uri: Uri = url.parse().unwrap();
+            let filter = filter.clone();
+            async move { filter.filter(&uri).await }
+        };
+
+        assert!(!allowed("https://example.com/private/a.png").await);
+        assert!(!allowed("https://example.com/%70rivate/a.png").await);
+        assert!(!allowed("https://example.com/%70%72ivate/a.png").await);
+        assert!(!allowed("https://example.com/x/../private/a.png").await);
+        assert!(!allowed("https://example.com/./x/%2e%2e/private/a.png").await);
+        assert!(!allowed("https://example.com/nft/../../private/a.png").await);
+        assert!(allowed("https://example.com/privates/a.png").await);
+        assert!(allowed("https://example.com/%zzprivate/a.png").await);
+    }
+
+    #[tokio::test]
+    async fn test_domain_filter() {
+        let config = DomainFilterConfig {
+            rules: vec![
+                rule("evil.example.com", None, FilterAction::Deny),
+                rule(".example.com", Some("/nft/"), FilterAction::Allow),
+                rule("*.ipfs.io", None, FilterAction::Allow),
+            ],
+            default_action: FilterAction::Deny,
+            reload_interval: None,
+        };
+        let filter = DomainFilter::new(&config);
+        let allowed = |url: &str| {
+            let uri: Uri = url.parse().unwrap();
+            let filter = filter.clone();
+            async move { filter.filter(&uri).await }
+        };
+
+        assert!(allowed("https://WWW.Example.com./nft/1.png").await);
+        assert!(!allowed("https://www.example.com/other/1.png").await);
+        assert!(!allowed("https://evil.example.com/nft/1.png").await);
+        assert!(allowed("https://gateway.ipfs.io/ipfs/Qm").await);
+        assert!(!allowed("https://unknown.com/nft/1.png").await);
+        assert!(allowed("https://www.example.com/%6eft/1.png").await);
+        assert!(!allowed("https://www.example.com/nft/../other/1.png").await);
+        assert!(!allowed("https://www.example.com/nft/%2e%2e/other/1.png").await);
+
+        let mut reloaded = config.clone();
+        reloaded.default_action = FilterAction::Allow;
+        assert!(filter.reload(&reloaded));
+        assert!(!filter.reload(&reloaded));
+        assert!(allowed("https://unknown.com/nft/1.png").await);
+        assert!(!allowed("https://evil.example.com/nft/1.png").await);
+    }
+}
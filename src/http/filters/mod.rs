@@ -1,14 +1,18 @@
+use async_trait::async_trait;
 use hyper::Uri;
 use serde::Deserialize;
 
+pub mod destination;
+pub mod domain;
 pub mod private_network;
 
-#[derive(Deserialize, Clone)]
+#[derive(Deserialize, Clone, Debug, PartialEq)]
 pub enum FilterAction {
     Allow,
     Deny,
 }
 
+#[async_trait]
 pub trait UriFilter {
-    fn filter(&self, uri: &Uri) -> bool;
+    async fn filter(&self, uri: &Uri) -> bool;
 }
@@ -1,3 +1,6 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
 use hyper::Uri;
 use log::{debug, error, warn};
 
@@ -6,19 +9,20 @@ use crate::dns::DnsResolver;
 use super::UriFilter;
 
 pub struct PrivateNetworkFilter {
-    dns_resolver: Box<dyn DnsResolver + Send + Sync>,
+    dns_resolver: Arc<dyn DnsResolver + Send + Sync>,
 }
 
 impl PrivateNetworkFilter {
-    pub fn new(dns_resolver: Box<dyn DnsResolver + Send + Sync>) -> PrivateNetworkFilter {
+    pub fn new(dns_resolver: Arc<dyn DnsResolver + Send + Sync>) -> PrivateNetworkFilter {
         PrivateNetworkFilter { dns_resolver }
     }
 }
 
+#[async_trait]
 impl UriFilter for PrivateNetworkFilter {
-    fn filter(&self, uri: &Uri) -> bool {
+    async fn filter(&self, uri: &Uri) -> bool {
         match uri.host() {
-            Some(host) => match self.dns_resolver.resolve(host) {
+            Some(host) => match self.dns_resolver.resolve(host).await {
                 Ok(ips) => {
                     debug!("Dns resolution, host:{}, ips:{:?}", host, ips);
                     ips.iter()
@@ -41,66 +45,66 @@ impl UriFilter for PrivateNetworkFilter {
 mod tests {
     use std::net::IpAddr;
 
-    use crate::dns::{DummyDnsResolver, StandardDnsResolver};
+    use crate::dns::{DnsConfig, DummyDnsResolver, StandardDnsResolver};
 
     use super::*;
 
-    #[test]
-    fn test_block_private_1() {
+    #[tokio::test]
+    async fn test_block_private_1() {
         let private_ip: IpAddr = "10.0.0.2".parse().unwrap();
         let dns_resolver = DummyDnsResolver {
             resolved_address: vec![private_ip],
         };
 
-        let filter = PrivateNetworkFilter::new(Box::new(dns_resolver));
+        let filter = PrivateNetworkFilter::new(Arc::new(dns_resolver));
         let private_uri = "http://localhost:8080/image.png".parse().unwrap();
-        assert!(!filter.filter(&private_uri));
+        assert!(!filter.filter(&private_uri).await);
     }
 
-    #[test]
-    fn test_block_private_2() {
-        let dns_resolver = StandardDnsResolver {};
-        let filter = PrivateNetworkFilter::new(Box::new(dns_resolver));
+    #[tokio::test]
+    async fn test_block_private_2() {
+        let dns_resolver = StandardDnsResolver::new(&DnsConfig::default()).unwrap();
+        let filter = PrivateNetworkFilter::new(Arc::new(dns_resolver));
         let private_uri = "http://localhost:8080/image.png".parse().unwrap();
-        assert!(!filter.filter(&private_uri));
+        assert!(!filter.filter(&private_uri).await);
     }
 
-    #[test]
-    fn test_allow_global() {
+    #[tokio::test]
+    async fn test_allow_global() {
         let global_ip1: IpAddr = "8.8.8.8".parse().unwrap();
         let resolver2 = DummyDnsResolver {
             resolved_address: vec![global_ip1],
         };
-        let filter = PrivateNetworkFilter::new(Box::new(resolver2));
+        let filter = PrivateNetworkFilter::new(Arc::new(resolver2));
         let global_uri = "https://www.google.com/image.png".parse().unwrap();
-        assert!(filter.filter(&global_uri));
+        assert!(filter.filter(&global_uri).await);
     }
 
-    #[test]
-    fn test_block_private_global_mix() {
+    #[tokio::test]
+    async fn test_block_private_global_mix() {
         let private_ip1: IpAddr = "172.16.10.14".parse().unwrap();
         let global_ip1: IpAddr = "8.8.8.8".parse().unwrap();
         let dns_resolver = DummyDnsResolver {
             resolved_address: vec![global_ip1, private_ip1],
         };
-        let filter = PrivateNetworkFilter::new(Box::new(dns_resolver));
+        let filter = PrivateNetworkFilter::new(Arc::new(dns_resolver));
         let global_uri = "https://www.google.com/image.png".parse().unwrap();
-        assert!(!filter.filter(&global_uri));
+        assert!(!filter.filter(&global_uri).await);
     }
 
-    #[test]
-    fn test_block_link_local() {
-        let dns_resolver = StandardDnsResolver {};
-        let filter = PrivateNetworkFilter::new(Box::new(dns_resolver));
+    #[tokio::test]
+    async fn test_block_link_local() {
+        let dns_resolver = StandardDnsResolver::new(&DnsConfig::default()).unwrap();
+        let filter = PrivateNetworkFilter::new(Arc::new(dns_resolver));
         let global_uri = "https://169.254.10.254/image.png".parse().unwrap();
-        assert!(!filter.filter(&global_uri));
+        assert!(!filter.filter(&global_uri).await);
     }
 
-    #[test]
-    fn test_block_broadcast() {
-        let dns_resolver = StandardDnsResolver {};
-        let filter = PrivateNetworkFilter::new(Box::new(dns_resolver));
+    #[tokio::test]
+    async fn test_block_broadcast() {
+        let dns_resolver = StandardDnsResolver::new(&DnsConfig::default()).unwrap();
+        let filter = PrivateNetworkFilter::new(Arc::new(dns_resolver));
         let global_uri = "https://255.255.255.255/image.png".parse().unwrap();
-        assert!(!filter.filter(&global_uri));
+        assert!(!filter.filter(&global_uri).await);
     }
 }
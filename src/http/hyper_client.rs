@@ -1,10 +1,12 @@
 use std::error::Error;
 use std::io::ErrorKind;
+use std::sync::Arc;
 use std::time::Duration;
 
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use http_body_util::{BodyExt, Full};
-use hyper::body::Bytes;
+use hyper::body::{Body, Bytes};
 use hyper::Uri;
 use hyper::{Method, Request};
 use hyper_timeout::TimeoutConnector;
@@ -12,23 +14,38 @@ use hyper_tls::HttpsConnector;
 use hyper_util::client::legacy::connect::HttpConnector;
 use hyper_util::client::legacy::Client;
 use hyper_util::rt::TokioExecutor;
-use log::error;
+use log::{debug, error, warn};
 
-use hyper_util::client::legacy::connect::dns::GaiResolver;
 use uuid::Uuid;
 
-use super::{HttpClientProvider, StatusCode, CODE_CONNECTION_ERROR, CODE_IO_ERROR, CODE_TIMEOUT};
+use super::resolver::{BlockedAddressError, GlobalAddressResolver};
+use super::{
+    HttpClientProvider, HttpResponse, StatusCode, CODE_BLOCKED_ADDRESS, CODE_CONNECTION_ERROR,
+    CODE_DOCUMENT_TOO_LARGE, CODE_IO_ERROR, CODE_TIMEOUT,
+};
+use crate::dns::DnsResolver;
 use crate::document::Document;
+use crate::utils::remove_dot_segments;
 
 pub struct HyperHttpClient {
-    client: Client<TimeoutConnector<HttpsConnector<HttpConnector<GaiResolver>>>, Full<Bytes>>,
-    _max_document_size: Option<u64>,
+    client:
+        Client<TimeoutConnector<HttpsConnector<HttpConnector<GlobalAddressResolver>>>, Full<Bytes>>,
+    max_document_size: Option<u64>,
     useragent: Option<String>,
 }
 
 impl HyperHttpClient {
-    pub fn new(max_document_size: Option<u64>, timeout: u64, useragent: Option<String>) -> Self {
-        let https = HttpsConnector::new();
+    pub fn new(
+        max_document_size: Option<u64>,
+        timeout: u64,
+        useragent: Option<String>,
+        dns_resolver: Arc<dyn DnsResolver + Send + Sync>,
+    ) -> Self {
+        // Hosts are resolved again when connecting, only global addresses
+        // are connected to
+        let mut http = HttpConnector::new_with_resolver(GlobalAddressResolver::new(dns_resolver));
+        http.enforce_http(false);
+        let https = HttpsConnector::new_with_connector(http);
         let mut connector = TimeoutConnector::new(https);
 
         connector.set_connect_timeout(Some(Duration::from_secs(timeout)));
@@ -38,15 +55,119 @@ impl HyperHttpClient {
         let client = Client::builder(TokioExecutor::new()).build::<_, Full<Bytes>>(connector);
         HyperHttpClient {
             client,
-            _max_document_size: max_document_size,
+            max_document_size,
             useragent,
         }
     }
+
+    /// Reads the body, aborting as soon as more than `max_size` bytes
+    /// have been received
+    async fn read_body<B>(
+        req_id: &Uuid,
+        mut body: B,
+        size_hint: Option<u64>,
+        max_size: Option<u64>,
+    ) -> Result<Bytes, StatusCode>
+    where
+        B: Body<Data = Bytes> + Unpin,
+        B::Error: std::fmt::Display,
+    {
+        // The declared length is only trusted as far as the limit
+        let capacity = match (size_hint, max_size) {
+            (Some(hint), Some(max)) => hint.min(max),
+            _ => 0,
+        };
+        let mut bytes = Vec::with_capacity(capacity as usize);
+        while let Some(frame) = body.frame().await {
+            let frame = frame.map_err(|e| {
+                error!("Hyper Client Error: {}", e);
+                CODE_IO_ERROR
+            })?;
+            if let Ok(data) = frame.into_data() {
+                if max_size.is_some_and(|max| (bytes.len() + data.len()) as u64 > max) {
+                    warn!(
+                        "Document exceeds max document size while streaming, id={}, max_size={:?}",
+                        req_id, max_size
+                    );
+                    return Err(CODE_DOCUMENT_TOO_LARGE);
+                }
+                bytes.extend_from_slice(&data);
+            }
+        }
+        Ok(Bytes::from(bytes))
+    }
+
+    /// Parses a `Retry-After` header, given either in seconds or as a date
+    fn retry_after(value: &str) -> Option<Duration> {
+        let value = value.trim();
+        if let Ok(seconds) = value.parse::<u64>() {
+            return Some(Duration::from_secs(seconds));
+        }
+        let date = DateTime::parse_from_rfc2822(value).ok()?;
+        Some(
+            (date.with_timezone(&Utc) - Utc::now())
+                .to_std()
+                .unwrap_or_default(),
+        )
+    }
+
+    /// Resolves the `Location` of a redirect, which may be relative to the
+    /// url that was requested, as described in RFC 3986, section 5.2.2.
+    /// Fragments are dropped, they are never sent to the origin.
+    fn redirect_location(base: &Uri, location: &str) -> Option<Uri> {
+        let location = location.trim();
+        let location = location.split('#').next().unwrap_or_default();
+        let has_scheme = location.split_once(':').is_some_and(|(scheme, _)| {
+            scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+                && scheme
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+        });
+        if has_scheme {
+            let uri = location.parse::<Uri>().ok()?;
+            return (uri.scheme().is_some() && uri.authority().is_some()).then_some(uri);
+        }
+
+        let scheme = base.scheme_str()?;
+        let (reference, query) = match location.split_once('?') {
+            Some((reference, query)) => (reference, Some(query)),
+            None => (location, None),
+        };
+        let (authority, path, query) = if let Some(rest) = reference.strip_prefix("//") {
+            let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+            (authority, remove_dot_segments(path), query)
+        } else if reference.is_empty() {
+            (
+                base.authority()?.as_str(),
+                base.path().to_string(),
+                query.or(base.query()),
+            )
+        } else if reference.starts_with('/') {
+            (
+                base.authority()?.as_str(),
+                remove_dot_segments(reference),
+                query,
+            )
+        } else {
+            let path = base.path();
+            let directory = &path[..path.rfind('/').map(|i| i + 1).unwrap_or(0)];
+            (
+                base.authority()?.as_str(),
+                remove_dot_segments(&format!("{}{}", directory, reference)),
+                query,
+            )
+        };
+        let absolute = match query {
+            Some(query) => format!("{}://{}{}?{}", scheme, authority, path, query),
+            None => format!("{}://{}{}", scheme, authority, path),
+        };
+        absolute.parse().ok()
+    }
 }
 
 #[async_trait]
 impl HttpClientProvider for HyperHttpClient {
-    async fn fetch(&self, req_id: &Uuid, uri: &Uri) -> Result<Document, StatusCode> {
+    async fn fetch(&self, req_id: &Uuid, uri: &Uri) -> Result<HttpResponse, StatusCode> {
         let request = Request::builder().method(Method::GET).uri(uri.clone());
         let request = if let Some(useragent) = &self.useragent {
             request.header("user-agent", useragent)
@@ -66,42 +187,190 @@ impl HttpClientProvider for HyperHttpClient {
                     }
                 }
             }
+            let blocked = std::iter::successors(error.source(), |e| (*e).source()).any(|e| {
+                e.downcast_ref::<std::io::Error>()
+                    .and_then(|e| e.get_ref())
+                    .is_some_and(|e| e.is::<BlockedAddressError>())
+            });
+            if blocked {
+                return CODE_BLOCKED_ADDRESS;
+            }
             CODE_CONNECTION_ERROR
         })?;
         match response.status() {
             hyper::StatusCode::OK => {
                 let headers = response.headers().clone();
-                let bytes = response
-                    .collect()
-                    .await
-                    .map_err(|e| {
-                        error!("Hyper Client Error: {}", e);
-                        CODE_IO_ERROR
-                    })
-                    .map(|b| b.to_bytes())?;
-
-                let content_length = headers
+                let declared_length = headers
                     .get(hyper::header::CONTENT_LENGTH)
                     .and_then(|h| {
                         String::from_utf8(h.as_bytes().to_vec())
                             .map(|s| s.parse::<u64>().ok())
                             .ok()
                     })
-                    .flatten()
-                    .unwrap_or(bytes.len() as u64);
+                    .flatten();
+                if let (Some(length), Some(max)) = (declared_length, self.max_document_size) {
+                    if length > max {
+                        warn!(
+                            "Document exceeds max document size, id={}, content_length={}, max_size={}",
+                            req_id, length, max
+                        );
+                        return Err(CODE_DOCUMENT_TOO_LARGE);
+                    }
+                }
+                let bytes = HyperHttpClient::read_body(
+                    req_id,
+                    response.into_body(),
+                    declared_length,
+                    self.max_document_size,
+                )
+                .await?;
+
+                let content_length = declared_length.unwrap_or(bytes.len() as u64);
                 let content_type = headers
                     .get(hyper::header::CONTENT_TYPE)
                     .and_then(|h| String::from_utf8(h.as_bytes().to_vec()).ok())
                     .unwrap_or_default();
-                Ok(Document {
+                Ok(HttpResponse::Document(Document {
                     id: *req_id,
                     content_type,
                     content_length,
                     bytes,
                     url: uri.to_string(),
+                }))
+            }
+            status_code @ (hyper::StatusCode::MOVED_PERMANENTLY
+            | hyper::StatusCode::FOUND
+            | hyper::StatusCode::SEE_OTHER
+            | hyper::StatusCode::TEMPORARY_REDIRECT
+            | hyper::StatusCode::PERMANENT_REDIRECT) => response
+                .headers()
+                .get(hyper::header::LOCATION)
+                .and_then(|h| h.to_str().ok())
+                .and_then(|location| HyperHttpClient::redirect_location(uri, location))
+                .map(|location| {
+                    debug!(
+                        "Redirected, id={}, status={}, location={}",
+                        req_id, status_code, location
+                    );
+                    HttpResponse::Redirect(location)
                 })
+                .ok_or(status_code.as_u16()),
+            status_code @ (hyper::StatusCode::TOO_MANY_REQUESTS
+            | hyper::StatusCode::BAD_GATEWAY
+            | hyper::StatusCode::SERVICE_UNAVAILABLE
+            | hyper::StatusCode::GATEWAY_TIMEOUT) => {
+                let retry_after = response
+                    .headers()
+                    .get(hyper::header::RETRY_AFTER)
+                    .and_then(|h| h.to_str().ok())
+                    .and_then(HyperHttpClient::retry_after);
+                Ok(HttpResponse::Unavailable(status_code.as_u16(), retry_after))
             }
             status_code => Err(status_code.as_u16()),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::dns::DummyDnsResolver;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_blocked_address() {
+        let dns_resolver = DummyDnsResolver {
+            resolved_address: vec!["127.0.0.1".parse().unwrap()],
+        };
+        let client = HyperHttpClient::new(None, 5, None, Arc::new(dns_resolver));
+        let uri: Uri = "http://internal.example.com/image.png".parse().unwrap();
+        let result = client.fetch(&Uuid::new_v4(), &uri).await;
+        assert!(matches!(result, Err(CODE_BLOCKED_ADDRESS)));
+    }
+
+    #[tokio::test]
+    async fn test_read_body() {
+        let req_id = Uuid::new_v4();
+        let body = || Full::new(Bytes::from(vec![0_u8; 100]));
+
+        let bytes = HyperHttpClient::read_body(&req_id, body(), Some(100), Some(100))
+            .await
+            .unwrap();
+        assert_eq!(bytes.len(), 100);
+
+        let bytes = HyperHttpClient::read_body(&req_id, body(), None, None)
+            .await
+            .unwrap();
+        assert_eq!(bytes.len(), 100);
+
+        // A missing or understated content length does not lift the limit
+        let result = HyperHttpClient::read_body(&req_id, body(), Some(10), Some(50)).await;
+        assert_eq!(result, Err(CODE_DOCUMENT_TOO_LARGE));
+        let result = HyperHttpClient::read_body(&req_id, body(), None, Some(99)).await;
+        assert_eq!(result, Err(CODE_DOCUMENT_TOO_LARGE));
+    }
+
+    #[test]
+    fn test_redirect_location() {
+        let base: Uri = "https://example.com/nft/1/image.png?size=2"
+            .parse()
+            .unwrap();
+        let resolve = |location: &str| {
+            HyperHttpClient::redirect_location(&base, location).map(|u| u.to_string())
+        };
+        assert_eq!(
+            resolve("http://cdn.example.net/a.png"),
+            Some("http://cdn.example.net/a.png".to_string())
+        );
+        assert_eq!(
+            resolve("//cdn.example.net/a.png"),
+            Some("https://cdn.example.net/a.png".to_string())
+        );
+        assert_eq!(
+            resolve("/a.png"),
+            Some("https://example.com/a.png".to_string())
+        );
+        assert_eq!(
+            resolve("b.png?v=1"),
+            Some("https://example.com/nft/1/b.png?v=1".to_string())
+        );
+        assert_eq!(
+            resolve("?v=2"),
+            Some("https://example.com/nft/1/image.png?v=2".to_string())
+        );
+        assert_eq!(
+            resolve("#preview"),
+            Some("https://example.com/nft/1/image.png?size=2".to_string())
+        );
+        assert_eq!(
+            resolve("../2/./b.png#preview"),
+            Some("https://example.com/nft/2/b.png".to_string())
+        );
+        assert_eq!(
+            resolve("/a/../../b.png"),
+            Some("https://example.com/b.png".to_string())
+        );
+        assert_eq!(
+            resolve("//cdn.example.net/x/../a.png?v=3"),
+            Some("https://cdn.example.net/a.png?v=3".to_string())
+        );
+        assert_eq!(resolve("data:image/png;base64,AAAA"), None);
+        assert_eq!(resolve("not a url"), None);
+    }
+
+    #[test]
+    fn test_retry_after() {
+        assert_eq!(
+            HyperHttpClient::retry_after(" 120 "),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            HyperHttpClient::retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+        let date = (Utc::now() + chrono::Duration::seconds(60)).to_rfc2822();
+        let wait = HyperHttpClient::retry_after(&date).unwrap();
+        assert!(wait > Duration::from_secs(55) && wait <= Duration::from_secs(60));
+        assert_eq!(HyperHttpClient::retry_after("soon"), None);
+    }
+}
@@ -1,36 +1,68 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
 use async_trait::async_trait;
 use hyper::http::uri::Scheme;
 use hyper::Uri;
 use log::{debug, error, info, warn};
 use uuid::Uuid;
 
-use crate::config::{Host, IpfsGatewayConfig};
+use crate::config::{Configuration, Host, IpfsGatewayConfig};
+use crate::dns::DnsResolver;
 use crate::document::Document;
 use crate::http::hyper_client::HyperHttpClient;
 use crate::metrics;
+use crate::moderation::SupportedMimeTypes;
 use crate::rpc::error::Errors;
 
 use self::filters::UriFilter;
+use self::origin::OriginGuard;
+use self::retry::RetryPolicy;
 
 pub mod filters;
 pub mod hyper_client;
+pub mod origin;
+pub mod resolver;
+pub mod retry;
 
 type StatusCode = u16;
 
 const CODE_CONNECTION_ERROR: StatusCode = 900_u16;
 const CODE_TIMEOUT: StatusCode = 901_u16;
-const CODE_IO_ERROR: StatusCode = 901_u16;
+const CODE_DOCUMENT_TOO_LARGE: StatusCode = 902_u16;
+const CODE_BLOCKED_ADDRESS: StatusCode = 903_u16;
+const CODE_IO_ERROR: StatusCode = 904_u16;
+
+// Redirects followed when no limit is configured
+const DEFAULT_MAX_REDIRECTS: u32 = 5_u32;
+
+// Content type given to documents that are not a recognised image
+const UNKNOWN_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// Outcome of a single request made by an `HttpClientProvider`
+pub enum HttpResponse {
+    Document(Document),
+    /// The origin redirected to this location, which is not followed by the
+    /// provider itself
+    Redirect(Uri),
+    /// The origin answered 429, 502, 503 or 504, optionally with the delay
+    /// from its `Retry-After` header
+    Unavailable(StatusCode, Option<Duration>),
+}
 
 #[async_trait]
 pub trait HttpClientProvider {
     // TODO: Not happy with this signature, need something better
-    async fn fetch(&self, req_id: &Uuid, url: &Uri) -> Result<Document, StatusCode>;
+    async fn fetch(&self, req_id: &Uuid, url: &Uri) -> Result<HttpResponse, StatusCode>;
 }
 
 pub struct HttpClientWrapper {
     client: Box<dyn HttpClientProvider + Send + Sync>,
     ipfs_config: IpfsGatewayConfig,
     uri_filters: Vec<Box<dyn UriFilter + Send + Sync>>,
+    max_redirects: u32,
+    origins: OriginGuard,
+    retries: RetryPolicy,
 }
 
 #[derive(PartialEq, Eq, Debug)]
@@ -57,6 +89,9 @@ impl HttpClientWrapper {
         client: Box<dyn HttpClientProvider + Send + Sync>,
         ipfs_config: IpfsGatewayConfig,
         uri_filters: Vec<Box<dyn UriFilter + Send + Sync>>,
+        max_redirects: u32,
+        origins: OriginGuard,
+        retries: RetryPolicy,
     ) -> Self {
         assert!(
             !uri_filters.is_empty(),
@@ -66,6 +101,9 @@ impl HttpClientWrapper {
             client,
             ipfs_config,
             uri_filters,
+            max_redirects,
+            origins,
+            retries,
         }
     }
 
@@ -145,7 +183,10 @@ impl HttpClientWrapper {
         };
 
         let result = self.do_fetch(req_id, &uri).await;
-        if result.is_err()
+        // Every gateway serves the same oversized document
+        if result
+            .as_ref()
+            .is_err_and(|e| *e != Errors::DocumentTooLarge)
             && parsed_uri.scheme == UriScheme::Ipfs
             && self.ipfs_config.fallback.is_some()
         {
@@ -159,24 +200,130 @@ impl HttpClientWrapper {
         }
     }
 
-    async fn do_fetch(&self, req_id: &Uuid, uri: &Uri) -> Result<Document, Errors> {
-        let filter_results = self
-            .uri_filters
-            .iter()
-            .map(|f| f.filter(uri))
-            .reduce(|a, b| a & b);
+    /// Replaces the content type reported by the origin with the one detected
+    /// from the document bytes. Gateways often report generic types for valid
+    /// images and origins may label anything as an image.
+    fn sniff_content_type(req_id: &Uuid, mut document: Document) -> Document {
+        let declared = document
+            .content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        let detected = document
+            .detect_content_type()
+            .unwrap_or(UNKNOWN_CONTENT_TYPE);
+        let declared_type = SupportedMimeTypes::from_string(&declared);
+        let matched = declared == detected
+            || (declared_type != SupportedMimeTypes::Unsupported
+                && declared_type == SupportedMimeTypes::from_string(detected));
+
+        if !matched {
+            warn!(
+                "Content type mismatch, id={}, declared={:?}, detected={}",
+                req_id, document.content_type, detected
+            );
+            metrics::DOCUMENT_TYPE
+                .with_label_values(&[detected, "mismatch"])
+                .inc();
+        }
+        metrics::DOCUMENT_TYPE
+            .with_label_values(&[detected, "fetched"])
+            .inc();
+        document.content_type = detected.to_string();
+        document
+    }
+
+    async fn check_filters(&self, req_id: &Uuid, uri: &Uri) -> Result<(), Errors> {
+        let mut filter_results = None;
+        for f in self.uri_filters.iter() {
+            filter_results = Some(f.filter(uri).await & filter_results.unwrap_or(true));
+        }
 
         match filter_results {
-            Some(true) => match self.client.fetch(req_id, uri).await {
-                Ok(document) => {
+            Some(true) => Ok(()),
+            Some(false) => {
+                warn!("Invalid destination host for id:{}", req_id);
+                metrics::URI_FILTER_BLOCKED.inc();
+                Err(Errors::InvalidOrBlockedHost)
+            }
+            None => {
+                warn!("Invalid destination host for id:{}", req_id);
+                metrics::URI_FILTER_BLOCKED.inc();
+                Err(Errors::InvalidOrBlockedHost)
+            }
+        }
+    }
+
+    /// Fetches the document, following at most `max_redirects` redirects.
+    /// Every uri filter is applied to each redirect target before it is
+    /// requested, and each request counts against its host's concurrency
+    /// limit and circuit breaker. Transient failures are retried as allowed by
+    /// the retry policy, within one budget shared by every hop.
+    async fn do_fetch(&self, req_id: &Uuid, uri: &Uri) -> Result<Document, Errors> {
+        let started = Instant::now();
+        let mut uri = uri.clone();
+        for hop in 0..=self.max_redirects {
+            self.check_filters(req_id, &uri).await?;
+            let mut attempt = 0;
+            let response = loop {
+                let permit = self
+                    .origins
+                    .acquire(req_id, uri.host().unwrap_or_default())
+                    .await?;
+                let response = self.client.fetch(req_id, &uri).await;
+                permit.record(match &response {
+                    Ok(HttpResponse::Unavailable(code, _)) | Err(code) => Err(*code),
+                    Ok(_) => Ok(()),
+                });
+                drop(permit);
+                match self.retries.delay(attempt, started.elapsed(), &response) {
+                    Some(delay) => {
+                        warn!(
+                            "Retrying fetch for id={}, attempt={}, delay_ms={}, url={}",
+                            req_id,
+                            attempt + 1,
+                            delay.as_millis(),
+                            uri
+                        );
+                        metrics::DOCUMENT.with_label_values(&["retried"]).inc();
+                        tokio::time::sleep(delay).await;
+                        attempt += 1;
+                    }
+                    None => break response,
+                }
+            };
+            match response {
+                Ok(HttpResponse::Redirect(_)) if hop == self.max_redirects => break,
+                Ok(HttpResponse::Redirect(location)) => {
+                    metrics::DOCUMENT.with_label_values(&["redirected"]).inc();
+                    info!(
+                        "Following redirect for id={}, hop={}, from={}, to={}",
+                        req_id,
+                        hop + 1,
+                        uri,
+                        location
+                    );
+                    match location.scheme() {
+                        Some(s) if *s == Scheme::HTTP || *s == Scheme::HTTPS => {}
+                        _ => {
+                            warn!(
+                                "Redirect to unsupported scheme, id={}, location={}",
+                                req_id, location
+                            );
+                            return Err(Errors::UnsupportedUriScheme);
+                        }
+                    }
+                    uri = location;
+                }
+                Ok(HttpResponse::Document(document)) => {
                     info!(
                         "Document fetched for id={}, content_length={:?}, content_type={:?}",
                         req_id, document.content_length, document.content_type
                     );
                     metrics::DOCUMENT.with_label_values(&["fetched"]).inc();
-                    metrics::DOCUMENT_TYPE
-                        .with_label_values(&[document.content_type.as_str()])
-                        .inc();
+                    let document = HttpClientWrapper::sniff_content_type(req_id, document);
                     metrics::TRAFFIC
                         .with_label_values(&["fetched"])
                         .inc_by(document.bytes.len() as u64);
@@ -184,10 +331,10 @@ impl HttpClientWrapper {
                         .with_label_values(&["size_bytes"])
                         .observe(document.bytes.len() as f64);
                     metrics::HTTP_CLIENT_CODES.with_label_values(&["200"]).inc();
-                    Ok(document)
+                    return Ok(document);
                 }
 
-                Err(code) => {
+                Ok(HttpResponse::Unavailable(code, _)) | Err(code) => {
                     metrics::DOCUMENT.with_label_values(&["fetch_error"]).inc();
                     metrics::HTTP_CLIENT_CODES
                         .with_label_values(&[code.to_string().as_str()])
@@ -196,20 +343,25 @@ impl HttpClientWrapper {
                         "Unable to fetch document, id={}, response_code={}, url={}",
                         req_id, code, uri
                     );
-                    Err(Errors::FetchFailed)
+                    return match code {
+                        CODE_DOCUMENT_TOO_LARGE => {
+                            metrics::DOCUMENT.with_label_values(&["too_large"]).inc();
+                            Err(Errors::DocumentTooLarge)
+                        }
+                        CODE_BLOCKED_ADDRESS => Err(Errors::InvalidOrBlockedHost),
+                        _ => Err(Errors::FetchFailed),
+                    };
                 }
-            },
-            Some(false) => {
-                warn!("Invalid destination host for id:{}", req_id);
-                metrics::URI_FILTER_BLOCKED.inc();
-                Err(Errors::InvalidOrBlockedHost)
-            }
-            None => {
-                warn!("Invalid destination host for id:{}", req_id);
-                metrics::URI_FILTER_BLOCKED.inc();
-                Err(Errors::InvalidOrBlockedHost)
             }
         }
+        warn!(
+            "Too many redirects for id={}, max_redirects={}",
+            req_id, self.max_redirects
+        );
+        metrics::DOCUMENT
+            .with_label_values(&["too_many_redirects"])
+            .inc();
+        Err(Errors::TooManyRedirects)
     }
 }
 
@@ -217,36 +369,56 @@ pub struct HttpClientFactory;
 
 impl HttpClientFactory {
     pub fn get_provider(
-        ipfs_config: IpfsGatewayConfig,
-        max_document_size: Option<u64>,
+        config: &Configuration,
         uri_filters: Vec<Box<dyn UriFilter + Send + Sync>>,
-        timeout: u64,
-        useragent: Option<String>,
+        dns_resolver: Arc<dyn DnsResolver + Send + Sync>,
     ) -> HttpClientWrapper {
         assert!(
             !uri_filters.is_empty(),
             "No URI filters provided. This is insecure, check code. Exiting..."
         );
 
+        let origins = OriginGuard::new(&config.origins.clone().unwrap_or_default(), &config.ipfs);
         HttpClientWrapper::new(
-            Box::new(HyperHttpClient::new(max_document_size, timeout, useragent)),
-            ipfs_config,
+            Box::new(HyperHttpClient::new(
+                config.max_document_size,
+                config.timeout,
+                config.client_useragent.clone(),
+                dns_resolver,
+            )),
+            config.ipfs.clone(),
             uri_filters,
+            config.max_redirects.unwrap_or(DEFAULT_MAX_REDIRECTS),
+            origins,
+            RetryPolicy::new(
+                &config.retries.clone().unwrap_or_default(),
+                Duration::from_secs(config.timeout),
+            ),
         )
     }
 }
 
 #[cfg(test)]
 pub mod tests {
-    use std::{collections::HashMap, net::IpAddr, sync::Mutex};
-
+    use std::{
+        collections::{HashMap, VecDeque},
+        net::IpAddr,
+        sync::Mutex,
+    };
+
+    use super::origin::OriginConfig;
+    use super::retry::RetryConfig;
     use super::*;
-    use crate::dns::{DummyDnsResolver, StandardDnsResolver};
+    use crate::dns::{DnsConfig, DummyDnsResolver, StandardDnsResolver};
     use filters::private_network::PrivateNetworkFilter;
     use hyper::body::Bytes;
+    use image::{DynamicImage, ImageOutputFormat};
+    use std::io::Cursor;
 
     pub struct DummyHttpClient {
         store: Mutex<HashMap<String, Document>>,
+        redirects: HashMap<String, Uri>,
+        failures: Mutex<HashMap<String, VecDeque<StatusCode>>>,
     }
 
     impl Default for DummyHttpClient {
@@ -259,6 +431,8 @@ pub mod tests {
         pub fn new() -> Self {
             DummyHttpClient {
                 store: Mutex::new(HashMap::new()),
+                redirects: HashMap::new(),
+                failures: Mutex::new(HashMap::new()),
             }
         }
 
@@ -266,15 +440,47 @@ pub mod tests {
             let mut store = self.store.lock().unwrap();
             store.insert(url.to_string(), document);
         }
+
+        pub fn set_redirect(&mut self, url: &str, location: &str) {
+            self.redirects
+                .insert(url.to_string(), location.parse().unwrap());
+        }
+
+        /// Queues a failure returned by the next request to the url, before
+        /// any document or redirect set for it
+        pub fn push_failure(&mut self, url: &str, code: StatusCode) {
+            self.failures
+                .get_mut()
+                .unwrap()
+                .entry(url.to_string())
+                .or_default()
+                .push_back(code);
+        }
     }
 
     #[async_trait]
     impl HttpClientProvider for DummyHttpClient {
-        async fn fetch(&self, _: &Uuid, url: &Uri) -> Result<Document, StatusCode> {
-            let store = self.store.lock().unwrap();
+        async fn fetch(&self, _: &Uuid, url: &Uri) -> Result<HttpResponse, StatusCode> {
             let url = url.to_string();
+            let failure = self
+                .failures
+                .lock()
+                .unwrap()
+                .get_mut(&url)
+                .and_then(|codes| codes.pop_front());
+            match failure {
+                Some(code @ (429 | 502 | 503 | 504)) => {
+                    return Ok(HttpResponse::Unavailable(code, None))
+                }
+                Some(code) => return Err(code),
+                None => {}
+            }
+            if let Some(location) = self.redirects.get(&url) {
+                return Ok(HttpResponse::Redirect(location.clone()));
+            }
+            let store = self.store.lock().unwrap();
             match store.get(&url) {
-                Some(document) => Ok(document.clone()),
+                Some(document) => Ok(HttpResponse::Document(document.clone())),
                 None => Err(404),
             }
         }
@@ -297,9 +503,9 @@ pub mod tests {
     #[tokio::test]
     async fn test_fetch_block_localhost() {
         let url = "http://localhost/abcd";
-        let dns_resolver = StandardDnsResolver {};
+        let dns_resolver = StandardDnsResolver::new(&DnsConfig::default()).unwrap();
         let uri_filters: Vec<Box<dyn UriFilter + Send + Sync>> =
-            vec![Box::new(PrivateNetworkFilter::new(Box::new(dns_resolver)))];
+            vec![Box::new(PrivateNetworkFilter::new(Arc::new(dns_resolver)))];
         let http_client = DummyHttpClient::new();
 
         let ipfs_config = IpfsGatewayConfig {
@@ -312,7 +518,16 @@ pub mod tests {
             fallback: None,
         };
 
-        let provider = HttpClientWrapper::new(Box::new(http_client), ipfs_config, uri_filters);
+        let origins = OriginGuard::new(&OriginConfig::default(), &ipfs_config);
+
+        let provider = HttpClientWrapper::new(
+            Box::new(http_client),
+            ipfs_config,
+            uri_filters,
+            5,
+            origins,
+            RetryPolicy::new(&RetryConfig::default(), Duration::from_secs(10)),
+        );
         // Test the result
         let result = provider.fetch(&Uuid::new_v4(), url).await;
         assert!(result.is_err());
@@ -335,7 +550,7 @@ pub mod tests {
             resolved_address: ip_vec,
         };
         let uri_filters: Vec<Box<dyn UriFilter + Send + Sync>> =
-            vec![Box::new(PrivateNetworkFilter::new(Box::new(dns_resolver)))];
+            vec![Box::new(PrivateNetworkFilter::new(Arc::new(dns_resolver)))];
         let http_client = DummyHttpClient::new();
 
         let ipfs_config = IpfsGatewayConfig {
@@ -348,7 +563,16 @@ pub mod tests {
             fallback: None,
         };
 
-        let provider = HttpClientWrapper::new(Box::new(http_client), ipfs_config, uri_filters);
+        let origins = OriginGuard::new(&OriginConfig::default(), &ipfs_config);
+
+        let provider = HttpClientWrapper::new(
+            Box::new(http_client),
+            ipfs_config,
+            uri_filters,
+            5,
+            origins,
+            RetryPolicy::new(&RetryConfig::default(), Duration::from_secs(10)),
+        );
         // Test the result
         let result = provider.fetch(&Uuid::new_v4(), ipfs_url).await;
         assert!(result.is_err());
@@ -371,7 +595,7 @@ pub mod tests {
             resolved_address: ip_vec,
         };
         let uri_filters: Vec<Box<dyn UriFilter + Send + Sync>> =
-            vec![Box::new(PrivateNetworkFilter::new(Box::new(dns_resolver)))];
+            vec![Box::new(PrivateNetworkFilter::new(Arc::new(dns_resolver)))];
         let mut http_client = DummyHttpClient::new();
 
         let ipfs_config = IpfsGatewayConfig {
@@ -392,7 +616,16 @@ pub mod tests {
         let mock_url = "https://localhost.com:443/ipfs/abcdef";
         http_client.set(mock_url, construct_document(mock_url));
 
-        let provider = HttpClientWrapper::new(Box::new(http_client), ipfs_config, uri_filters);
+        let origins = OriginGuard::new(&OriginConfig::default(), &ipfs_config);
+
+        let provider = HttpClientWrapper::new(
+            Box::new(http_client),
+            ipfs_config,
+            uri_filters,
+            5,
+            origins,
+            RetryPolicy::new(&RetryConfig::default(), Duration::from_secs(10)),
+        );
 
         // Test the result
         let result = provider.fetch(&Uuid::new_v4(), ipfs_url).await;
@@ -401,6 +634,156 @@ pub mod tests {
         assert_eq!(result.unwrap().url, mock_url.to_string());
     }
 
+    /// Tests that redirects are followed up to the hop limit and that
+    /// the uri filters are applied to every redirect target
+    #[tokio::test]
+    async fn test_fetch_redirects() {
+        let ipfs_config = IpfsGatewayConfig {
+            primary: Host {
+                protocol: "http".to_string(),
+                host: "127.0.0.1".to_string(),
+                port: 1337,
+                path: "/ipfs".to_string(),
+            },
+            fallback: None,
+        };
+        let construct_provider = |max_redirects| {
+            let mut http_client = DummyHttpClient::new();
+            http_client.set_redirect("http://8.8.8.8/a.png", "http://8.8.4.4/b.png");
+            http_client.set_redirect("http://8.8.4.4/b.png", "http://1.1.1.1/c.png");
+            http_client.set(
+                "http://1.1.1.1/c.png",
+                construct_document("http://1.1.1.1/c.png"),
+            );
+            http_client.set_redirect("http://8.8.8.8/internal.png", "http://127.0.0.1/d.png");
+            http_client.set(
+                "http://127.0.0.1/d.png",
+                construct_document("http://127.0.0.1/d.png"),
+            );
+            http_client.set_redirect("http://8.8.8.8/file.png", "ftp://8.8.8.8/file.png");
+            let uri_filters: Vec<Box<dyn UriFilter + Send + Sync>> =
+                vec![Box::new(PrivateNetworkFilter::new(Arc::new(
+                    StandardDnsResolver::new(&DnsConfig::default()).unwrap(),
+                )))];
+            HttpClientWrapper::new(
+                Box::new(http_client),
+                ipfs_config.clone(),
+                uri_filters,
+                max_redirects,
+                OriginGuard::new(&OriginConfig::default(), &ipfs_config),
+                RetryPolicy::new(&RetryConfig::default(), Duration::from_secs(10)),
+            )
+        };
+
+        let provider = construct_provider(2);
+        let result = provider
+            .fetch(&Uuid::new_v4(), "http://8.8.8.8/a.png")
+            .await;
+        assert_eq!(result.unwrap().url, "http://1.1.1.1/c.png");
+
+        let result = provider
+            .fetch(&Uuid::new_v4(), "http://8.8.8.8/internal.png")
+            .await;
+        assert_eq!(result.err(), Some(Errors::InvalidOrBlockedHost));
+
+        let result = provider
+            .fetch(&Uuid::new_v4(), "http://8.8.8.8/file.png")
+            .await;
+        assert_eq!(result.err(), Some(Errors::UnsupportedUriScheme));
+
+        let provider = construct_provider(1);
+        let result = provider
+            .fetch(&Uuid::new_v4(), "http://8.8.8.8/a.png")
+            .await;
+        assert_eq!(result.err(), Some(Errors::TooManyRedirects));
+    }
+
+    /// Tests that transient failures are retried up to the limit and that
+    /// other failures are not retried
+    #[tokio::test]
+    async fn test_fetch_retries() {
+        let ipfs_config = IpfsGatewayConfig {
+            primary: Host {
+                protocol: "http".to_string(),
+                host: "127.0.0.1".to_string(),
+                port: 1337,
+                path: "/ipfs".to_string(),
+            },
+            fallback: None,
+        };
+        let mut http_client = DummyHttpClient::new();
+        for (url, failures) in [
+            ("http://8.8.8.8/a.png", vec![503, CODE_CONNECTION_ERROR]),
+            ("http://8.8.8.8/b.png", vec![CODE_TIMEOUT, 429, 502]),
+            ("http://8.8.8.8/c.png", vec![404]),
+            ("http://8.8.8.8/d.png", vec![500]),
+            ("http://8.8.8.8/e.png", vec![CODE_IO_ERROR]),
+        ] {
+            http_client.set(url, construct_document(url));
+            for code in failures {
+                http_client.push_failure(url, code);
+            }
+        }
+        let dns_resolver = DummyDnsResolver {
+            resolved_address: vec!["8.8.8.8".parse().unwrap()],
+        };
+        let uri_filters: Vec<Box<dyn UriFilter + Send + Sync>> =
+            vec![Box::new(PrivateNetworkFilter::new(Arc::new(dns_resolver)))];
+        let retry_config = RetryConfig {
+            max_retries: 2,
+            initial_backoff: 1,
+            max_backoff: 10,
+        };
+        let provider = HttpClientWrapper::new(
+            Box::new(http_client),
+            ipfs_config.clone(),
+            uri_filters,
+            5,
+            OriginGuard::new(&OriginConfig::default(), &ipfs_config),
+            RetryPolicy::new(&retry_config, Duration::from_secs(10)),
+        );
+
+        let result = provider
+            .fetch(&Uuid::new_v4(), "http://8.8.8.8/a.png")
+            .await;
+        assert_eq!(result.unwrap().url, "http://8.8.8.8/a.png");
+
+        for url in [
+            "http://8.8.8.8/b.png",
+            "http://8.8.8.8/c.png",
+            "http://8.8.8.8/d.png",
+            "http://8.8.8.8/e.png",
+        ] {
+            let result = provider.fetch(&Uuid::new_v4(), url).await;
+            assert_eq!(result.err(), Some(Errors::FetchFailed));
+        }
+    }
+
+    #[test]
+    fn test_sniff_content_type() {
+        let url = "http://localhost/image.png";
+        let mut png = Cursor::new(Vec::new());
+        DynamicImage::new_rgb8(4, 4)
+            .write_to(&mut png, ImageOutputFormat::Png)
+            .unwrap();
+        let mut document = construct_document(url);
+        document.bytes = Bytes::from(png.into_inner());
+
+        // Generic gateway content types are replaced
+        document.content_type = "application/octet-stream".to_string();
+        let result = HttpClientWrapper::sniff_content_type(&Uuid::new_v4(), document.clone());
+        assert_eq!(result.content_type, "image/png");
+
+        document.content_type = "image/png; charset=binary".to_string();
+        let result = HttpClientWrapper::sniff_content_type(&Uuid::new_v4(), document);
+        assert_eq!(result.content_type, "image/png");
+
+        // Text labelled as an image is not treated as one
+        let result =
+            HttpClientWrapper::sniff_content_type(&Uuid::new_v4(), construct_document(url));
+        assert_eq!(result.content_type, UNKNOWN_CONTENT_TYPE);
+    }
+
     #[test]
     fn test_parse_uri() {
         let url1 = "https://localhost:3422/image.png";
@@ -0,0 +1,374 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::warn;
+use serde::Deserialize;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use uuid::Uuid;
+
+use crate::circuit_breaker::{BreakerState, CircuitBreaker, CircuitBreakerConfig};
+use crate::config::IpfsGatewayConfig;
+use crate::metrics;
+use crate::rpc::error::Errors;
+
+use super::{StatusCode, CODE_CONNECTION_ERROR, CODE_IO_ERROR, CODE_TIMEOUT};
+
+// Hosts without a request for this long are forgotten first
+const ORIGIN_IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct OriginConfig {
+    /// Maximum number of requests in flight to a single host, IPFS gateways
+    /// are not limited
+    pub max_concurrency: usize,
+    /// Time in seconds a request waits for a busy host before failing
+    pub max_wait: u64,
+    /// Maximum number of hosts tracked at once. Hosts with requests in
+    /// flight or a tripped breaker are never forgotten, requests to new
+    /// hosts fail while all tracked hosts are in that state.
+    pub max_hosts: u64,
+    /// Breaker applied to each host, failures being connection errors,
+    /// timeouts, errors while reading a body and 5xx responses
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+}
+
+impl Default for OriginConfig {
+    fn default() -> Self {
+        OriginConfig {
+            max_concurrency: 16,
+            max_wait: 10,
+            max_hosts: 10000,
+            circuit_breaker: None,
+        }
+    }
+}
+
+struct OriginUsage {
+    in_flight: i64,
+    last_used: Instant,
+}
+
+struct Origin {
+    permits: Option<Arc<Semaphore>>,
+    breaker: CircuitBreaker,
+    // Gauges are only updated while holding this lock, so that a label is
+    // never removed while another request updates it
+    usage: Mutex<OriginUsage>,
+}
+
+impl Origin {
+    /// Whether the host can be forgotten without losing anything but its
+    /// count of consecutive failures. Requests hold a reference to the
+    /// origin from the moment they start waiting for a slot.
+    fn is_evictable(origin: &Arc<Origin>) -> bool {
+        Arc::strong_count(origin) == 1 && origin.breaker.state() == BreakerState::Closed
+    }
+
+    /// Exports the breaker state, hosts are only labelled while their
+    /// breaker is not closed
+    fn report_state(&self, host: &str) {
+        let _usage = self.usage.lock().unwrap();
+        match self.breaker.state() {
+            BreakerState::Closed => {
+                let _ = metrics::ORIGIN_BREAKER_STATE.remove_label_values(&[host]);
+            }
+            state => metrics::ORIGIN_BREAKER_STATE
+                .with_label_values(&[host])
+                .set(state.as_i64()),
+        }
+    }
+
+    /// Counts a request in flight, hosts are only labelled while they have
+    /// requests in flight
+    fn add_in_flight(&self, host: &str, delta: i64) {
+        let mut usage = self.usage.lock().unwrap();
+        usage.in_flight += delta;
+        usage.last_used = Instant::now();
+        if usage.in_flight > 0 {
+            metrics::ORIGIN_IN_FLIGHT
+                .with_label_values(&[host])
+                .set(usage.in_flight);
+        } else {
+            let _ = metrics::ORIGIN_IN_FLIGHT.remove_label_values(&[host]);
+        }
+    }
+
+    fn last_used(&self) -> Instant {
+        self.usage.lock().unwrap().last_used
+    }
+}
+
+/// Limits the requests in flight to each host and stops requests to hosts
+/// that keep failing, so that one slow or dead origin cannot tie up fetches
+/// until they time out.
+pub struct OriginGuard {
+    origins: Mutex<HashMap<String, Arc<Origin>>>,
+    max_hosts: usize,
+    gateway_hosts: Vec<String>,
+    max_concurrency: usize,
+    max_wait: Duration,
+    breaker_config: CircuitBreakerConfig,
+}
+
+/// Held for the duration of a request to a host
+pub struct OriginPermit {
+    host: String,
+    origin: Arc<Origin>,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl OriginPermit {
+    /// Records the outcome of the request with the host's breaker
+    pub fn record(&self, result: Result<(), StatusCode>) {
+        match result {
+            Err(code) if is_origin_failure(code) => self.origin.breaker.record_failure(),
+            _ => self.origin.breaker.record_success(),
+        }
+        self.origin.report_state(&self.host);
+    }
+}
+
+impl Drop for OriginPermit {
+    fn drop(&mut self) {
+        self.origin.add_in_flight(&self.host, -1);
+    }
+}
+
+/// Whether an error means the host itself is unhealthy
+fn is_origin_failure(code: StatusCode) -> bool {
+    code == CODE_CONNECTION_ERROR
+        || code == CODE_TIMEOUT
+        || code == CODE_IO_ERROR
+        || (500..600).contains(&code)
+}
+
+impl OriginGuard {
+    pub fn new(config: &OriginConfig, ipfs_config: &IpfsGatewayConfig) -> Self {
+        let gateway_hosts = std::iter::once(&ipfs_config.primary)
+            .chain(ipfs_config.fallback.iter())
+            .map(|gateway| gateway.host.to_lowercase())
+            .collect();
+        OriginGuard {
+            origins: Mutex::new(HashMap::new()),
+            max_hosts: config.max_hosts.max(1) as usize,
+            gateway_hosts,
+            max_concurrency: config.max_concurrency.max(1),
+            max_wait: Duration::from_secs(config.max_wait),
+            breaker_config: config.circuit_breaker.clone().unwrap_or_default(),
+        }
+    }
+
+    /// Returns the tracked state of the host, `None` if the host is new and
+    /// no tracked host can be forgotten to make room for it
+    fn origin(&self, host: &str) -> Option<Arc<Origin>> {
+        let mut origins = self.origins.lock().unwrap();
+        if let Some(origin) = origins.get(host) {
+            return Some(origin.clone());
+        }
+
+        if origins.len() >= self.max_hosts {
+            // Forget every idle host, or at least the least recently used one
+            origins.retain(|_, origin| {
+                !(Origin::is_evictable(origin)
+                    && origin.last_used().elapsed() >= ORIGIN_IDLE_TIMEOUT)
+            });
+            if origins.len() >= self.max_hosts {
+                let oldest = origins
+                    .iter()
+                    .filter(|(_, origin)| Origin::is_evictable(origin))
+                    .min_by_key(|(_, origin)| origin.last_used())
+                    .map(|(host, _)| host.clone());
+                match oldest {
+                    Some(oldest) => {
+                        origins.remove(&oldest);
+                    }
+                    None => return None,
+                }
+            }
+        }
+
+        let permits = (!self.gateway_hosts.iter().any(|h| h == host))
+            .then(|| Arc::new(Semaphore::new(self.max_concurrency)));
+        let origin = Arc::new(Origin {
+            permits,
+            breaker: CircuitBreaker::new(&self.breaker_config),
+            usage: Mutex::new(OriginUsage {
+                in_flight: 0,
+                last_used: Instant::now(),
+            }),
+        });
+        origins.insert(host.to_string(), origin.clone());
+        Some(origin)
+    }
+
+    /// Waits for the host to have a free slot, failing if its breaker is open
+    /// or no slot frees up within `max_wait`
+    pub async fn acquire(&self, req_id: &Uuid, host: &str) -> Result<OriginPermit, Errors> {
+        let host = host.to_lowercase();
+        let origin = match self.origin(&host) {
+            Some(origin) => origin,
+            None => {
+                warn!(
+                    "Too many hosts with requests in flight or failing, id={}, host={}",
+                    req_id, host
+                );
+                metrics::ORIGIN_REJECTED.with_label_values(&["hosts"]).inc();
+                return Err(Errors::HostBusy);
+            }
+        };
+
+        let allowed = origin.breaker.allow();
+        origin.report_state(&host);
+        if !allowed {
+            warn!(
+                "Circuit breaker open, skipping host, id={}, host={}",
+                req_id, host
+            );
+            metrics::ORIGIN_REJECTED.with_label_values(&["open"]).inc();
+            return Err(Errors::HostUnavailable);
+        }
+
+        let permit = match &origin.permits {
+            Some(permits) => {
+                match tokio::time::timeout(self.max_wait, permits.clone().acquire_owned()).await {
+                    Ok(Ok(permit)) => Some(permit),
+                    _ => {
+                        warn!(
+                            "Too many requests in flight to host, id={}, host={}",
+                            req_id, host
+                        );
+                        metrics::ORIGIN_REJECTED.with_label_values(&["busy"]).inc();
+                        return Err(Errors::HostBusy);
+                    }
+                }
+            }
+            None => None,
+        };
+        origin.add_in_flight(&host, 1);
+        Ok(OriginPermit {
+            host,
+            origin,
+            _permit: permit,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus::core::Collector;
+
+    use crate::config::Host;
+
+    use super::*;
+
+    fn construct_guard() -> OriginGuard {
+        let ipfs_config = IpfsGatewayConfig {
+            primary: Host {
+                protocol: "https".to_string(),
+                host: "gateway.example.com".to_string(),
+                port: 443,
+                path: "/ipfs".to_string(),
+            },
+            fallback: None,
+        };
+        let config = OriginConfig {
+            max_concurrency: 2,
+            max_wait: 0,
+            max_hosts: 3,
+            circuit_breaker: Some(CircuitBreakerConfig {
+                failure_threshold: 2,
+                cooldown: 60,
+            }),
+        };
+        OriginGuard::new(&config, &ipfs_config)
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_limit() {
+        let guard = construct_guard();
+        let req_id = Uuid::new_v4();
+
+        let first = guard.acquire(&req_id, "slow.com").await.unwrap();
+        let _second = guard.acquire(&req_id, "SLOW.com").await.unwrap();
+        assert!(matches!(
+            guard.acquire(&req_id, "slow.com").await,
+            Err(Errors::HostBusy)
+        ));
+        assert!(guard.acquire(&req_id, "other.com").await.is_ok());
+
+        drop(first);
+        assert!(guard.acquire(&req_id, "slow.com").await.is_ok());
+
+        // Gateways serve every IPFS fetch and are not limited
+        let gateway: Vec<_> = futures::future::join_all(
+            (0..4).map(|_| guard.acquire(&req_id, "gateway.example.com")),
+        )
+        .await;
+        assert!(gateway.iter().all(|p| p.is_ok()));
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker() {
+        let guard = construct_guard();
+        let req_id = Uuid::new_v4();
+
+        for code in [404, CODE_TIMEOUT, 503] {
+            let permit = guard.acquire(&req_id, "dead.com").await.unwrap();
+            permit.record(Err(code));
+        }
+        assert!(matches!(
+            guard.acquire(&req_id, "dead.com").await,
+            Err(Errors::HostUnavailable)
+        ));
+
+        // A success in between resets the failure count
+        for result in [Err(CODE_CONNECTION_ERROR), Ok(()), Err(500)] {
+            let permit = guard.acquire(&req_id, "alive.com").await.unwrap();
+            permit.record(result);
+        }
+        assert!(guard.acquire(&req_id, "alive.com").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_host_eviction() {
+        let guard = construct_guard();
+        let req_id = Uuid::new_v4();
+        let in_flight = |host: &str| {
+            metrics::ORIGIN_IN_FLIGHT.collect()[0]
+                .get_metric()
+                .iter()
+                .find(|m| m.get_label()[0].get_value() == host)
+                .map(|m| m.get_gauge().get_value() as i64)
+        };
+
+        // Hosts with requests in flight or a tripped breaker are kept
+        let busy = guard.acquire(&req_id, "busy.evict.com").await.unwrap();
+        for _ in 0..2 {
+            let permit = guard.acquire(&req_id, "dead.evict.com").await.unwrap();
+            permit.record(Err(CODE_TIMEOUT));
+        }
+        for host in ["a.evict.com", "b.evict.com", "c.evict.com"] {
+            assert!(guard.acquire(&req_id, host).await.is_ok());
+        }
+        assert_eq!(guard.origins.lock().unwrap().len(), 3);
+        assert!(guard.origins.lock().unwrap().contains_key("busy.evict.com"));
+        assert!(matches!(
+            guard.acquire(&req_id, "dead.evict.com").await,
+            Err(Errors::HostUnavailable)
+        ));
+        assert_eq!(in_flight("busy.evict.com"), Some(1));
+
+        // Once every host is in use, new hosts are turned away
+        let _held = guard.acquire(&req_id, "d.evict.com").await.unwrap();
+        assert!(matches!(
+            guard.acquire(&req_id, "e.evict.com").await,
+            Err(Errors::HostBusy)
+        ));
+
+        // The in flight gauge is only labelled while requests are in flight
+        drop(busy);
+        assert_eq!(in_flight("busy.evict.com"), None);
+        assert!(guard.acquire(&req_id, "e.evict.com").await.is_ok());
+    }
+}
@@ -0,0 +1,118 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use hyper_util::client::legacy::connect::dns::Name;
+use log::warn;
+use tower_service::Service;
+
+use crate::dns::DnsResolver;
+use crate::metrics;
+
+/// Returned when a host resolves to an address that must not be connected to
+#[derive(Debug)]
+pub struct BlockedAddressError {
+    pub host: String,
+}
+
+impl std::fmt::Display for BlockedAddressError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Host resolved to a non global address, host={}",
+            self.host
+        )
+    }
+}
+
+impl std::error::Error for BlockedAddressError {}
+
+/// Resolves hosts for the http connector, refusing any host with a non
+/// global address. The uri filters resolve hosts separately, so without this
+/// a DNS server could answer a public address to the filters and an internal
+/// one when connecting.
+#[derive(Clone)]
+pub struct GlobalAddressResolver {
+    dns_resolver: Arc<dyn DnsResolver + Send + Sync>,
+}
+
+impl GlobalAddressResolver {
+    pub fn new(dns_resolver: Arc<dyn DnsResolver + Send + Sync>) -> Self {
+        GlobalAddressResolver { dns_resolver }
+    }
+}
+
+impl Service<Name> for GlobalAddressResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let dns_resolver = self.dns_resolver.clone();
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+            let ips = dns_resolver.resolve(&host).await?;
+            if ips.is_empty() || ips.iter().any(|ip| !ip.is_global()) {
+                warn!(
+                    "Connection refused to non global address, host={}, ips={:?}",
+                    host, ips
+                );
+                metrics::URI_FILTER_BLOCKED.inc();
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    BlockedAddressError { host },
+                ));
+            }
+            // The connector sets the port of each address
+            Ok(ips
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, 0))
+                .collect::<Vec<SocketAddr>>()
+                .into_iter())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::str::FromStr;
+
+    use crate::dns::DummyDnsResolver;
+
+    use super::*;
+
+    fn construct_resolver(ips: &[&str]) -> GlobalAddressResolver {
+        GlobalAddressResolver::new(Arc::new(DummyDnsResolver {
+            resolved_address: ips.iter().map(|ip| ip.parse().unwrap()).collect(),
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_resolve() {
+        let name = Name::from_str("example.com").unwrap();
+        let addrs: Vec<IpAddr> = construct_resolver(&["8.8.8.8", "1.1.1.1"])
+            .call(name.clone())
+            .await
+            .unwrap()
+            .map(|addr| addr.ip())
+            .collect();
+        assert_eq!(addrs.len(), 2);
+
+        for ips in [vec!["169.254.169.254"], vec!["8.8.8.8", "10.0.0.2"], vec![]] {
+            let err = construct_resolver(&ips)
+                .call(name.clone())
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+            assert!(err.get_ref().is_some_and(|e| e.is::<BlockedAddressError>()));
+        }
+    }
+}
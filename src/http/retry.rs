@@ -0,0 +1,144 @@
+use std::time::Duration;
+
+use rand::Rng;
+use serde::Deserialize;
+
+use super::{HttpResponse, StatusCode, CODE_CONNECTION_ERROR, CODE_TIMEOUT};
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct RetryConfig {
+    /// Number of retries after the first attempt
+    pub max_retries: u32,
+    /// Delay in milliseconds before the first retry, doubled for every
+    /// retry after it
+    pub initial_backoff: u64,
+    /// Upper bound in milliseconds on the delay before a retry. Origins
+    /// asking to wait longer through `Retry-After` are not retried.
+    pub max_backoff: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_retries: 2,
+            initial_backoff: 250,
+            max_backoff: 5000,
+        }
+    }
+}
+
+/// Decides whether and when a failed request is retried. Connection errors,
+/// timeouts and origins answering 429, 502, 503 or 504 are retried with an
+/// exponential backoff, randomized so that retries to a recovering origin
+/// are spread out. Other failures, including errors while reading a body,
+/// are never retried. No retry starts once `budget` has elapsed since the
+/// first attempt, so retries cannot stretch a fetch far beyond the request
+/// timeout.
+pub struct RetryPolicy {
+    max_retries: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    budget: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(config: &RetryConfig, budget: Duration) -> Self {
+        RetryPolicy {
+            max_retries: config.max_retries,
+            initial_backoff: Duration::from_millis(config.initial_backoff),
+            max_backoff: Duration::from_millis(config.max_backoff),
+            budget,
+        }
+    }
+
+    /// Delay before retrying after the given attempt, counted from 0, or
+    /// `None` if the request should not be retried. `elapsed` is the time
+    /// spent since the first attempt.
+    pub fn delay(
+        &self,
+        attempt: u32,
+        elapsed: Duration,
+        response: &Result<HttpResponse, StatusCode>,
+    ) -> Option<Duration> {
+        if attempt >= self.max_retries || elapsed >= self.budget {
+            return None;
+        }
+        let retry_after = match response {
+            Err(code) if *code == CODE_CONNECTION_ERROR || *code == CODE_TIMEOUT => None,
+            Ok(HttpResponse::Unavailable(_, retry_after)) => *retry_after,
+            _ => return None,
+        };
+
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2_u32.saturating_pow(attempt))
+            .min(self.max_backoff);
+        let backoff = backoff.mul_f64(rand::thread_rng().gen_range(0.5..=1.0));
+        let delay = match retry_after {
+            Some(wait) if wait > self.max_backoff => return None,
+            Some(wait) => wait.max(backoff),
+            None => backoff,
+        };
+        (elapsed + delay < self.budget).then_some(delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay() {
+        let policy = RetryPolicy::new(
+            &RetryConfig {
+                max_retries: 3,
+                initial_backoff: 100,
+                max_backoff: 300,
+            },
+            Duration::from_secs(10),
+        );
+        let unavailable = |retry_after: Option<u64>| {
+            Ok(HttpResponse::Unavailable(
+                503,
+                retry_after.map(Duration::from_secs),
+            ))
+        };
+
+        let delays: Vec<Duration> = (0..3)
+            .map(|attempt| {
+                policy
+                    .delay(attempt, Duration::ZERO, &Err(CODE_CONNECTION_ERROR))
+                    .unwrap()
+            })
+            .collect();
+        assert!(delays[0] >= Duration::from_millis(50) && delays[0] <= Duration::from_millis(100));
+        assert!(delays[1] >= Duration::from_millis(100) && delays[1] <= Duration::from_millis(200));
+        assert!(delays[2] >= Duration::from_millis(150) && delays[2] <= Duration::from_millis(300));
+        assert_eq!(policy.delay(3, Duration::ZERO, &Err(CODE_TIMEOUT)), None);
+
+        assert!(policy
+            .delay(0, Duration::ZERO, &unavailable(None))
+            .is_some());
+        assert_eq!(
+            policy
+                .delay(0, Duration::ZERO, &unavailable(Some(0)))
+                .map(|d| d > Duration::ZERO),
+            Some(true)
+        );
+        assert_eq!(policy.delay(0, Duration::ZERO, &unavailable(Some(1))), None);
+
+        for code in [404, 403, 500, 903, 904] {
+            assert_eq!(policy.delay(0, Duration::ZERO, &Err(code)), None);
+        }
+
+        // Retries stop once the budget is spent or would be by the delay
+        assert_eq!(
+            policy.delay(0, Duration::from_secs(10), &unavailable(None)),
+            None
+        );
+        assert_eq!(
+            policy.delay(0, Duration::from_millis(9950), &Err(CODE_TIMEOUT)),
+            None
+        );
+    }
+}
@@ -0,0 +1,127 @@
+use std::sync::Arc;
+use std::thread::available_parallelism;
+
+use log::error;
+use prometheus::IntGauge;
+use serde::Deserialize;
+use tokio::sync::Semaphore;
+
+use crate::{metrics, rpc::error::Errors};
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct ImagePoolConfig {
+    /// Maximum number of images decoded, resized, hashed or encoded at once
+    pub max_concurrency: usize,
+}
+
+impl Default for ImagePoolConfig {
+    fn default() -> Self {
+        ImagePoolConfig {
+            max_concurrency: available_parallelism().map(|n| n.get()).unwrap_or(4),
+        }
+    }
+}
+
+/// Keeps the gauge incremented for as long as it is alive
+struct GaugeGuard(IntGauge);
+
+impl GaugeGuard {
+    fn new(gauge: IntGauge) -> Self {
+        gauge.inc();
+        GaugeGuard(gauge)
+    }
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// Runs CPU heavy image work on Tokio's blocking threads so that it does not
+/// stall the async workers. At most `max_concurrency` jobs run at once, the
+/// rest wait for a permit.
+pub struct ImagePool {
+    permits: Arc<Semaphore>,
+}
+
+impl ImagePool {
+    pub fn new(config: &ImagePoolConfig) -> Self {
+        ImagePool {
+            permits: Arc::new(Semaphore::new(config.max_concurrency.max(1))),
+        }
+    }
+
+    pub async fn run<T, F>(&self, job: F) -> Result<T, Errors>
+    where
+        F: FnOnce() -> Result<T, Errors> + Send + 'static,
+        T: Send + 'static,
+    {
+        let queued = GaugeGuard::new(metrics::IMAGE_POOL.with_label_values(&["queued"]));
+        let permit = self.permits.clone().acquire_owned().await.map_err(|e| {
+            error!("Image pool closed, reason={}", e);
+            Errors::InternalError
+        })?;
+        drop(queued);
+
+        let running = GaugeGuard::new(metrics::IMAGE_POOL.with_label_values(&["running"]));
+        // The permit is held by the job itself, a cancelled request does not
+        // free it while the work is still running
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            let _running = running;
+            job()
+        })
+        .await
+        .map_err(|e| {
+            error!("Image job failed, reason={}", e);
+            Errors::InternalError
+        })?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use futures::future::join_all;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_run() {
+        let pool = ImagePool::new(&ImagePoolConfig { max_concurrency: 2 });
+        let active = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+
+        let jobs = (0..6).map(|i| {
+            let active = active.clone();
+            let peak = peak.clone();
+            pool.run(move || {
+                let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                sleep(Duration::from_millis(20));
+                active.fetch_sub(1, Ordering::SeqCst);
+                Ok(i)
+            })
+        });
+        let results = join_all(jobs).await;
+        assert_eq!(
+            results.into_iter().collect::<Result<Vec<_>, _>>(),
+            Ok(vec![0, 1, 2, 3, 4, 5])
+        );
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+
+        assert_eq!(
+            pool.run(|| Err::<(), _>(Errors::ImageTooLarge)).await,
+            Err(Errors::ImageTooLarge)
+        );
+        assert_eq!(
+            pool.run(|| -> Result<(), Errors> { panic!("job panicked") })
+                .await,
+            Err(Errors::InternalError)
+        );
+    }
+}
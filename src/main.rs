@@ -2,17 +2,23 @@
 extern crate tokio;
 
 pub mod aws;
+pub mod blurhash;
 pub mod cache;
+pub mod circuit_breaker;
 pub mod config;
 pub mod db;
 pub mod dns;
 pub mod document;
+pub mod hashlist;
 pub mod http;
+pub mod image_pool;
 pub mod logging;
+pub mod metadata;
 pub mod metrics;
 pub mod moderation;
 pub mod proxy;
 pub mod rpc;
+pub mod svg;
 pub mod utils;
 
 use std::{
@@ -108,5 +114,8 @@ fn main() {
     init_registry();
 
     info!("Starting proxy server");
-    runtime.block_on(run(config)).unwrap();
+    if let Err(e) = runtime.block_on(run(config)) {
+        error!("Proxy server stopped, reason={}", e);
+        std::process::exit(1);
+    }
 }
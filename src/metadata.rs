@@ -0,0 +1,459 @@
+use std::convert::TryInto;
+
+use image::ImageFormat;
+
+type GenericError = Box<dyn std::error::Error + Send + Sync>;
+
+const EXIF_ORIENTATION_TAG: u16 = 0x0112;
+const EXIF_TYPE_SHORT: u16 = 3;
+
+const JPEG_SOI: [u8; 2] = [0xFF, 0xD8];
+const JPEG_EOI: [u8; 2] = [0xFF, 0xD9];
+const JPEG_APP0: u8 = 0xE0;
+const JPEG_APP1: u8 = 0xE1;
+const JPEG_APP2: u8 = 0xE2;
+const JPEG_APP14: u8 = 0xEE;
+const JPEG_COM: u8 = 0xFE;
+const JPEG_SOS: u8 = 0xDA;
+const JPEG_EXIF_HEADER: &[u8] = b"Exif\0\0";
+const JPEG_JFIF_HEADER: &[u8] = b"JFIF\0";
+const JPEG_ICC_HEADER: &[u8] = b"ICC_PROFILE\0";
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+// Textual chunks and the modification time
+const PNG_METADATA_CHUNKS: [&[u8; 4]; 4] = [b"tEXt", b"zTXt", b"iTXt", b"tIME"];
+
+const WEBP_HEADER_LENGTH: usize = 12;
+const WEBP_EXIF_FLAG: u8 = 0x08;
+const WEBP_XMP_FLAG: u8 = 0x04;
+
+/// Reads a non default orientation from a TIFF structured EXIF block
+fn exif_orientation(tiff: &[u8]) -> Option<u16> {
+    let big_endian = match tiff.get(0..4)? {
+        b"MM\0*" => true,
+        b"II*\0" => false,
+        _ => return None,
+    };
+    let read_u16 = |offset: usize| {
+        let bytes = tiff.get(offset..offset + 2)?.try_into().ok()?;
+        Some(if big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    };
+    let read_u32 = |offset: usize| {
+        let bytes = tiff.get(offset..offset + 4)?.try_into().ok()?;
+        Some(if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    };
+
+    let ifd = read_u32(4)? as usize;
+    let entries = read_u16(ifd)? as usize;
+    (0..entries)
+        .map(|i| ifd + 2 + i * 12)
+        .find(|entry| read_u16(*entry) == Some(EXIF_ORIENTATION_TAG))
+        .filter(|entry| read_u16(entry + 2) == Some(EXIF_TYPE_SHORT))
+        .and_then(|entry| read_u16(entry + 8))
+        .filter(|orientation| (2..=8).contains(orientation))
+}
+
+/// A TIFF structured EXIF block holding nothing but the orientation
+fn orientation_exif(orientation: u16) -> Vec<u8> {
+    let mut tiff = b"MM\0*".to_vec();
+    tiff.extend_from_slice(&8_u32.to_be_bytes());
+    tiff.extend_from_slice(&1_u16.to_be_bytes());
+    tiff.extend_from_slice(&EXIF_ORIENTATION_TAG.to_be_bytes());
+    tiff.extend_from_slice(&EXIF_TYPE_SHORT.to_be_bytes());
+    tiff.extend_from_slice(&1_u32.to_be_bytes());
+    tiff.extend_from_slice(&orientation.to_be_bytes());
+    // Value padding, then no further IFDs
+    tiff.extend_from_slice(&[0_u8; 6]);
+    tiff
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(u32::MAX, |crc, byte| {
+        (0..8).fold(crc ^ *byte as u32, |crc, _| {
+            (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
+/// Removes EXIF, XMP, IPTC, comments and other application segments,
+/// keeping JFIF, Adobe and ICC profile segments. Data after the end of
+/// image marker, such as appended preview images, is dropped as well.
+pub fn strip_jpeg(bytes: &[u8]) -> Result<Vec<u8>, GenericError> {
+    if !bytes.starts_with(&JPEG_SOI) {
+        return Err("Missing JPEG start of image marker".into());
+    }
+    let mut segments: Vec<&[u8]> = Vec::new();
+    let mut orientation = None;
+    let mut pos = JPEG_SOI.len();
+    loop {
+        let marker = match bytes.get(pos..pos + 2) {
+            Some([0xFF, marker]) => *marker,
+            _ => return Err("Invalid or truncated JPEG segment".into()),
+        };
+        // Fill bytes and markers without a length
+        if marker == 0xFF {
+            pos += 1;
+            continue;
+        }
+        if marker == 0x01 || (0xD0..=0xD9).contains(&marker) {
+            segments.push(&bytes[pos..pos + 2]);
+            pos += 2;
+            continue;
+        }
+        if marker == JPEG_SOS {
+            break;
+        }
+
+        let length = bytes
+            .get(pos + 2..pos + 4)
+            .map(|l| u16::from_be_bytes([l[0], l[1]]) as usize)
+            .filter(|l| *l >= 2)
+            .ok_or("Invalid JPEG segment length")?;
+        let segment = bytes
+            .get(pos..pos + 2 + length)
+            .ok_or("Truncated JPEG segment")?;
+        let payload = &segment[4..];
+        let keep = match marker {
+            JPEG_APP0 => payload.starts_with(JPEG_JFIF_HEADER),
+            JPEG_APP1 => {
+                if let Some(tiff) = payload.strip_prefix(JPEG_EXIF_HEADER) {
+                    orientation = orientation.or_else(|| exif_orientation(tiff));
+                }
+                false
+            }
+            JPEG_APP2 => payload.starts_with(JPEG_ICC_HEADER),
+            JPEG_APP14 => true,
+            0xE3..=0xEF | JPEG_COM => false,
+            _ => true,
+        };
+        if keep {
+            segments.push(segment);
+        }
+        pos += segment.len();
+    }
+
+    // Scan data can only contain the end of image marker at its end
+    let end = bytes[pos..]
+        .windows(2)
+        .position(|w| w == JPEG_EOI)
+        .map_or(bytes.len(), |p| pos + p + 2);
+
+    let mut stripped = Vec::with_capacity(bytes.len());
+    stripped.extend_from_slice(&JPEG_SOI);
+    // A JFIF segment has to come first
+    let (jfif, others): (Vec<&[u8]>, Vec<&[u8]>) = segments
+        .into_iter()
+        .partition(|s| s.get(1) == Some(&JPEG_APP0));
+    jfif.iter().for_each(|s| stripped.extend_from_slice(s));
+    if let Some(orientation) = orientation {
+        let exif = [JPEG_EXIF_HEADER, &orientation_exif(orientation)].concat();
+        stripped.extend_from_slice(&[0xFF, JPEG_APP1]);
+        stripped.extend_from_slice(&(exif.len() as u16 + 2).to_be_bytes());
+        stripped.extend_from_slice(&exif);
+    }
+    others.iter().for_each(|s| stripped.extend_from_slice(s));
+    stripped.extend_from_slice(&bytes[pos..end]);
+    Ok(stripped)
+}
+
+/// Removes textual chunks, the modification time and EXIF other than the
+/// orientation. Color chunks such as `iCCP` are kept.
+pub fn strip_png(bytes: &[u8]) -> Result<Vec<u8>, GenericError> {
+    if !bytes.starts_with(PNG_SIGNATURE) {
+        return Err("Missing PNG signature".into());
+    }
+    let mut chunks = Vec::new();
+    let mut pos = PNG_SIGNATURE.len();
+    loop {
+        let header = bytes.get(pos..pos + 8).ok_or("Truncated PNG chunk")?;
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let chunk = bytes
+            .get(pos..pos + 12 + length)
+            .ok_or("Truncated PNG chunk")?;
+        pos += chunk.len();
+        chunks.push(chunk);
+        if &header[4..8] == b"IEND" {
+            break;
+        }
+    }
+
+    // Decoders also accept eXIf after the image data, so every chunk is
+    // looked at before any is written
+    let mut orientation = chunks
+        .iter()
+        .filter(|chunk| &chunk[4..8] == b"eXIf")
+        .find_map(|chunk| exif_orientation(&chunk[8..chunk.len() - 4]));
+    let mut stripped = Vec::with_capacity(bytes.len());
+    stripped.extend_from_slice(PNG_SIGNATURE);
+    for chunk in chunks {
+        let chunk_type = &chunk[4..8];
+        if chunk_type == b"eXIf"
+            || PNG_METADATA_CHUNKS
+                .iter()
+                .any(|t| t.as_slice() == chunk_type)
+        {
+            continue;
+        }
+        // The orientation is written back before the image data, where the
+        // specification places eXIf
+        if chunk_type == b"IDAT" {
+            if let Some(orientation) = orientation.take() {
+                let exif = orientation_exif(orientation);
+                let mut exif_chunk = b"eXIf".to_vec();
+                exif_chunk.extend_from_slice(&exif);
+                stripped.extend_from_slice(&(exif.len() as u32).to_be_bytes());
+                stripped.extend_from_slice(&exif_chunk);
+                stripped.extend_from_slice(&crc32(&exif_chunk).to_be_bytes());
+            }
+        }
+        stripped.extend_from_slice(chunk);
+    }
+    Ok(stripped)
+}
+
+/// Removes the XMP chunk and EXIF other than the orientation. Only
+/// extended format files carry metadata, others are returned unchanged.
+pub fn strip_webp(bytes: &[u8]) -> Result<Vec<u8>, GenericError> {
+    if bytes.len() < WEBP_HEADER_LENGTH || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WEBP" {
+        return Err("Missing WebP header".into());
+    }
+    let mut stripped = bytes[..WEBP_HEADER_LENGTH].to_vec();
+    let mut orientation = None;
+    let mut extended_header = None;
+    let mut pos = WEBP_HEADER_LENGTH;
+    while pos < bytes.len() {
+        let header = bytes.get(pos..pos + 8).ok_or("Truncated WebP chunk")?;
+        let fourcc = &header[0..4];
+        let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        // Chunks are padded to an even length
+        let end = (pos + 8 + length + (length & 1)).min(bytes.len());
+        let chunk = bytes.get(pos..end).ok_or("Truncated WebP chunk")?;
+        pos = end;
+
+        match fourcc {
+            b"EXIF" => {
+                let exif = chunk.get(8..8 + length).ok_or("Truncated WebP chunk")?;
+                // Some writers include the JPEG style header
+                let exif = exif.strip_prefix(JPEG_EXIF_HEADER).unwrap_or(exif);
+                orientation = orientation.or_else(|| exif_orientation(exif));
+            }
+            b"XMP " => {}
+            _ => {
+                if fourcc == b"VP8X" {
+                    extended_header = Some(stripped.len());
+                }
+                stripped.extend_from_slice(chunk);
+            }
+        }
+    }
+
+    if let Some(header) = extended_header {
+        let flags = header + 8;
+        stripped[flags] &= !(WEBP_EXIF_FLAG | WEBP_XMP_FLAG);
+        // EXIF goes after the image data
+        if let Some(orientation) = orientation {
+            let exif = orientation_exif(orientation);
+            stripped[flags] |= WEBP_EXIF_FLAG;
+            stripped.extend_from_slice(b"EXIF");
+            stripped.extend_from_slice(&(exif.len() as u32).to_le_bytes());
+            stripped.extend_from_slice(&exif);
+        }
+    }
+    let riff_length = (stripped.len() - 8) as u32;
+    stripped[4..8].copy_from_slice(&riff_length.to_le_bytes());
+    Ok(stripped)
+}
+
+/// Strips metadata from JPEG, PNG and WebP images. Returns `None` for
+/// other formats.
+pub fn strip(bytes: &[u8]) -> Option<Result<Vec<u8>, GenericError>> {
+    match image::guess_format(bytes).ok()? {
+        ImageFormat::Jpeg => Some(strip_jpeg(bytes)),
+        ImageFormat::Png => Some(strip_png(bytes)),
+        ImageFormat::WebP => Some(strip_webp(bytes)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{DynamicImage, ImageOutputFormat, RgbImage};
+
+    use super::*;
+
+    fn encode(format: ImageOutputFormat) -> Vec<u8> {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(16, 8, |x, y| {
+            image::Rgb([(x * 16) as u8, (y * 32) as u8, 64])
+        }));
+        let mut cursor = Cursor::new(Vec::new());
+        image.write_to(&mut cursor, format).unwrap();
+        cursor.into_inner()
+    }
+
+    /// EXIF holding a GPS like tag followed by the orientation, little endian
+    fn construct_exif(orientation: u16) -> Vec<u8> {
+        let mut tiff = b"II*\0".to_vec();
+        tiff.extend_from_slice(&8_u32.to_le_bytes());
+        tiff.extend_from_slice(&2_u16.to_le_bytes());
+        tiff.extend_from_slice(&0x8825_u16.to_le_bytes());
+        tiff.extend_from_slice(&4_u16.to_le_bytes());
+        tiff.extend_from_slice(&1_u32.to_le_bytes());
+        tiff.extend_from_slice(b"GPS!");
+        tiff.extend_from_slice(&EXIF_ORIENTATION_TAG.to_le_bytes());
+        tiff.extend_from_slice(&EXIF_TYPE_SHORT.to_le_bytes());
+        tiff.extend_from_slice(&1_u32.to_le_bytes());
+        tiff.extend_from_slice(&orientation.to_le_bytes());
+        tiff.extend_from_slice(&[0_u8; 6]);
+        tiff
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|w| w == needle)
+    }
+
+    fn jpeg_segment(marker: u8, payload: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0xFF, marker];
+        segment.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+        segment.extend_from_slice(payload);
+        segment
+    }
+
+    fn png_chunk(chunk_type: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(&[chunk_type, data].concat());
+        chunk.extend_from_slice(&crc32(&[chunk_type, data].concat()).to_be_bytes());
+        chunk
+    }
+
+    #[test]
+    fn test_exif_orientation() {
+        assert_eq!(exif_orientation(&construct_exif(6)), Some(6));
+        assert_eq!(exif_orientation(&orientation_exif(3)), Some(3));
+        assert_eq!(exif_orientation(&construct_exif(1)), None);
+        assert_eq!(exif_orientation(b"II*\0\xff\xff\xff\xff"), None);
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+    }
+
+    #[test]
+    fn test_strip_jpeg() {
+        let jpeg = encode(ImageOutputFormat::Jpeg(90));
+        let exif = [JPEG_EXIF_HEADER, &construct_exif(6)].concat();
+        let icc = [JPEG_ICC_HEADER, b"\x01\x01profile"].concat();
+        let jpeg = [
+            &jpeg[..2],
+            &jpeg_segment(JPEG_APP1, &exif),
+            &jpeg_segment(JPEG_APP2, &icc),
+            &jpeg_segment(0xED, b"Photoshop 3.0\0iptc"),
+            &jpeg_segment(JPEG_COM, b"serial 1234"),
+            &jpeg[2..],
+            b"trailing preview",
+        ]
+        .concat();
+
+        let stripped = strip(&jpeg).unwrap().unwrap();
+        assert!(!contains(&stripped, b"GPS!"));
+        assert!(!contains(&stripped, b"iptc"));
+        assert!(!contains(&stripped, b"serial"));
+        assert!(!contains(&stripped, b"trailing"));
+        assert!(contains(&stripped, b"ICC_PROFILE\0\x01\x01profile"));
+        assert!(contains(&stripped, &orientation_exif(6)));
+        // The JFIF segment written by the encoder is moved first
+        assert_eq!(&stripped[6..11], JPEG_JFIF_HEADER);
+        assert_eq!(
+            image::load_from_memory(&stripped).unwrap().to_rgb8(),
+            image::load_from_memory(&jpeg).unwrap().to_rgb8()
+        );
+
+        assert!(strip_jpeg(&jpeg[..40]).is_err());
+    }
+
+    #[test]
+    fn test_strip_png() {
+        let png = encode(ImageOutputFormat::Png);
+        // Signature and image header
+        let (head, rest) = png.split_at(33);
+        let png = [
+            head,
+            &png_chunk(b"tEXt", b"Author\0someone"),
+            &png_chunk(b"eXIf", &construct_exif(8)),
+            rest,
+        ]
+        .concat();
+
+        let stripped = strip(&png).unwrap().unwrap();
+        assert!(!contains(&stripped, b"someone"));
+        assert!(!contains(&stripped, b"GPS!"));
+        assert!(contains(
+            &stripped,
+            &png_chunk(b"eXIf", &orientation_exif(8))
+        ));
+        assert_eq!(
+            image::load_from_memory(&stripped).unwrap().to_rgb8(),
+            image::load_from_memory(&png).unwrap().to_rgb8()
+        );
+
+        // The orientation is kept when eXIf follows the image data
+        let png = encode(ImageOutputFormat::Png);
+        let (rest, end) = png.split_at(png.len() - 12);
+        let png = [rest, &png_chunk(b"eXIf", &construct_exif(6)), end].concat();
+        let stripped = strip(&png).unwrap().unwrap();
+        let exif = png_chunk(b"eXIf", &orientation_exif(6));
+        let exif_at = stripped.windows(exif.len()).position(|w| w == exif);
+        let idat_at = stripped.windows(4).position(|w| w == b"IDAT");
+        assert!(exif_at.is_some() && exif_at < idat_at);
+        assert!(!contains(&stripped, b"GPS!"));
+    }
+
+    #[test]
+    fn test_strip_webp() {
+        let webp = encode(ImageOutputFormat::WebP);
+        let mut vp8x = vec![WEBP_EXIF_FLAG | WEBP_XMP_FLAG, 0, 0, 0];
+        vp8x.extend_from_slice(&15_u32.to_le_bytes()[..3]);
+        vp8x.extend_from_slice(&7_u32.to_le_bytes()[..3]);
+        let chunk = |fourcc: &[u8], data: &[u8]| {
+            let mut chunk = fourcc.to_vec();
+            chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            chunk.extend_from_slice(data);
+            if data.len() % 2 == 1 {
+                chunk.push(0);
+            }
+            chunk
+        };
+        let mut webp = [
+            &webp[..12],
+            &chunk(b"VP8X", &vp8x),
+            &webp[12..],
+            &chunk(b"EXIF", &construct_exif(5)),
+            &chunk(b"XMP ", b"<x:xmpmeta>creator</x:xmpmeta>"),
+        ]
+        .concat();
+        let length = (webp.len() - 8) as u32;
+        webp[4..8].copy_from_slice(&length.to_le_bytes());
+
+        let stripped = strip(&webp).unwrap().unwrap();
+        assert!(!contains(&stripped, b"creator"));
+        assert!(!contains(&stripped, b"GPS!"));
+        assert!(contains(&stripped, &orientation_exif(5)));
+        assert_eq!(stripped[20], WEBP_EXIF_FLAG);
+        assert_eq!(
+            u32::from_le_bytes(stripped[4..8].try_into().unwrap()) as usize,
+            stripped.len() - 8
+        );
+        assert_eq!(
+            image::load_from_memory(&stripped).unwrap().to_rgb8(),
+            image::load_from_memory(&webp).unwrap().to_rgb8()
+        );
+
+        assert!(strip(b"GIF89a").is_none());
+    }
+}
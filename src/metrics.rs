@@ -23,6 +23,10 @@ lazy_static! {
         25000.0 * 1024_f64,
         50000.0 * 1024_f64,
     ];
+    static ref IMAGE_RESIZE_TIME_BUCKETS: Vec<f64> =
+        vec![1.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0,];
+    static ref DNS_LOOKUP_TIME_BUCKETS: Vec<f64> =
+        vec![0.1, 0.5, 1.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 5000.0,];
     pub static ref REGISTRY: Registry = Registry::new();
     pub static ref ERRORS: IntCounter = IntCounter::new("errors", "Total errors").unwrap();
     pub static ref ERRORS_RPC: IntCounterVec = IntCounterVec::new(
@@ -65,14 +69,32 @@ lazy_static! {
     )
     .unwrap();
     pub static ref DOCUMENT_TYPE: IntCounterVec = IntCounterVec::new(
-        Opts::new("document_type", "Document types by mime"),
-        &["mime_types"]
+        Opts::new("document_type", "Detected document types by mime"),
+        &["mime_types", "metric"]
     )
     .unwrap();
     pub static ref TRAFFIC: IntCounterVec =
         IntCounterVec::new(Opts::new("traffic", "Traffic stats in bytes"), &["metric"]).unwrap();
     pub static ref MODERATION: IntCounterVec =
         IntCounterVec::new(Opts::new("moderation", "Moderation stats"), &["metric"]).unwrap();
+    pub static ref MODERATION_BREAKER_STATE: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "moderation_breaker_state",
+            "Circuit breaker state by moderation provider, 0=closed, 1=half open, 2=open"
+        ),
+        &["provider"]
+    )
+    .unwrap();
+    pub static ref HASH_LIST_MATCHES: IntCounterVec = IntCounterVec::new(
+        Opts::new("hash_list_matches", "Hash list matches by match type"),
+        &["match_type"]
+    )
+    .unwrap();
+    pub static ref IMAGE_LIMITS: IntCounterVec = IntCounterVec::new(
+        Opts::new("image_limits", "Images rejected by decoding limit exceeded"),
+        &["limit"]
+    )
+    .unwrap();
     pub static ref MODERATION_CATEGORIES: IntCounterVec = IntCounterVec::new(
         Opts::new("moderation_categories", "Moderation Categories"),
         &["category"]
@@ -88,6 +110,44 @@ lazy_static! {
         "Number of times the filter blocked a host"
     )
     .unwrap();
+    pub static ref DNS_LOOKUP_TIME: HistogramVec = HistogramVec::new(
+        HistogramOpts::new(
+            "dns_lookup_time",
+            "DNS lookup time in milliseconds, cached answers included"
+        )
+        .buckets(DNS_LOOKUP_TIME_BUCKETS.clone()),
+        &["result"]
+    )
+    .unwrap();
+    pub static ref DNS_LOOKUP_FAILURES: IntCounterVec = IntCounterVec::new(
+        Opts::new("dns_lookup_failures", "Failed DNS lookups by reason"),
+        &["reason"]
+    )
+    .unwrap();
+    pub static ref ORIGIN_IN_FLIGHT: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "origin_in_flight",
+            "Requests in flight by destination hostname, only hosts with requests in flight are labelled"
+        ),
+        &["hostname"]
+    )
+    .unwrap();
+    pub static ref ORIGIN_BREAKER_STATE: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "origin_breaker_state",
+            "Circuit breaker state by destination hostname, 1=half open, 2=open, hosts with a closed breaker are not labelled"
+        ),
+        &["hostname"]
+    )
+    .unwrap();
+    pub static ref ORIGIN_REJECTED: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "origin_rejected",
+            "Requests not sent to a destination host, by reason"
+        ),
+        &["reason"]
+    )
+    .unwrap();
     pub static ref URI_DESTINATION_HOST: IntCounterVec = IntCounterVec::new(
         Opts::new("uri_source", "Counts requests by destination hostname"),
         &["hostname"]
@@ -106,6 +166,23 @@ lazy_static! {
         &["metric"]
     )
     .unwrap();
+    pub static ref IMAGE_RESIZE_TIME: HistogramVec = HistogramVec::new(
+        HistogramOpts::new(
+            "image_resize_time",
+            "Time spent resizing images for moderation in milliseconds, by stage"
+        )
+        .buckets(IMAGE_RESIZE_TIME_BUCKETS.clone()),
+        &["stage"]
+    )
+    .unwrap();
+    pub static ref IMAGE_POOL: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "image_pool",
+            "Image processing jobs waiting for or running on the blocking pool"
+        ),
+        &["state"]
+    )
+    .unwrap();
 }
 
 pub fn init_registry() {
@@ -130,6 +207,21 @@ pub fn init_registry() {
     REGISTRY
         .register(Box::new(URI_FILTER_BLOCKED.clone()))
         .unwrap();
+    REGISTRY
+        .register(Box::new(DNS_LOOKUP_TIME.clone()))
+        .unwrap();
+    REGISTRY
+        .register(Box::new(DNS_LOOKUP_FAILURES.clone()))
+        .unwrap();
+    REGISTRY
+        .register(Box::new(ORIGIN_IN_FLIGHT.clone()))
+        .unwrap();
+    REGISTRY
+        .register(Box::new(ORIGIN_BREAKER_STATE.clone()))
+        .unwrap();
+    REGISTRY
+        .register(Box::new(ORIGIN_REJECTED.clone()))
+        .unwrap();
     REGISTRY
         .register(Box::new(URI_DESTINATION_HOST.clone()))
         .unwrap();
@@ -137,9 +229,20 @@ pub fn init_registry() {
         .register(Box::new(URI_DESTINATION_PROTOCOL.clone()))
         .unwrap();
     REGISTRY.register(Box::new(IMAGE_RESIZE.clone())).unwrap();
+    REGISTRY
+        .register(Box::new(IMAGE_RESIZE_TIME.clone()))
+        .unwrap();
+    REGISTRY.register(Box::new(IMAGE_POOL.clone())).unwrap();
     REGISTRY
         .register(Box::new(MODERATION_CATEGORIES.clone()))
         .unwrap();
+    REGISTRY
+        .register(Box::new(MODERATION_BREAKER_STATE.clone()))
+        .unwrap();
+    REGISTRY
+        .register(Box::new(HASH_LIST_MATCHES.clone()))
+        .unwrap();
+    REGISTRY.register(Box::new(IMAGE_LIMITS.clone())).unwrap();
     #[cfg(not(target_os = "macos"))]
     let pc = ProcessCollector::for_self();
     #[cfg(not(target_os = "macos"))]
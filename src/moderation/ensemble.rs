@@ -0,0 +1,275 @@
+use async_trait::async_trait;
+use futures::future::join_all;
+use log::{debug, error, warn};
+use serde::Deserialize;
+
+use crate::{
+    config::{EnsembleConfig, ModerationConfig},
+    document::Document,
+    metrics,
+    rpc::error::Errors,
+};
+
+use super::{
+    common_supported_types, GenericError, ModerationCategories, ModerationProvider,
+    ModerationResponse, ModerationService, ProviderResult, SupportedMimeTypes,
+};
+
+// Fraction of the total weight that must flag a category when
+// using weighted voting and no threshold is configured
+const DEFAULT_WEIGHTED_THRESHOLD: f64 = 0.5_f64;
+
+/// How the categories returned by the ensemble members are combined
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub enum VotingStrategy {
+    /// A category flagged by any provider is included
+    Union,
+    /// A category must be flagged by more than half of the providers
+    Majority,
+    /// A category must be flagged by providers holding at least
+    /// `threshold` of the total weight
+    Weighted,
+}
+
+struct EnsembleMember {
+    provider: Box<dyn ModerationProvider + Send + Sync>,
+    weight: f64,
+}
+
+/// A moderation provider which submits each document to several providers
+/// concurrently and combines their verdicts using a voting strategy
+pub struct EnsembleModerationProvider {
+    members: Vec<EnsembleMember>,
+    strategy: VotingStrategy,
+    threshold: f64,
+}
+
+impl EnsembleModerationProvider {
+    pub async fn new(
+        config: &EnsembleConfig,
+        moderation_config: &ModerationConfig,
+    ) -> Result<Self, GenericError> {
+        if config.members.is_empty() {
+            return Err("Ensemble moderation requires at least one member provider".into());
+        }
+
+        let mut providers = Vec::new();
+        for member in &config.members {
+            if member.provider == ModerationService::Ensemble {
+                return Err("Ensemble moderation providers cannot be nested".into());
+            }
+            let weight = member.weight.unwrap_or(1_f64);
+            if weight <= 0_f64 {
+                return Err("Ensemble member weights must be greater than zero".into());
+            }
+            let aws = member.aws.as_ref().or(moderation_config.aws.as_ref());
+            providers.push((member.provider.build_provider(aws).await?, weight));
+        }
+
+        Ok(Self::from_providers(
+            providers,
+            config.strategy.clone(),
+            config.threshold.unwrap_or(DEFAULT_WEIGHTED_THRESHOLD),
+        ))
+    }
+
+    pub fn from_providers(
+        providers: Vec<(Box<dyn ModerationProvider + Send + Sync>, f64)>,
+        strategy: VotingStrategy,
+        threshold: f64,
+    ) -> Self {
+        EnsembleModerationProvider {
+            members: providers
+                .into_iter()
+                .map(|(provider, weight)| EnsembleMember { provider, weight })
+                .collect(),
+            strategy,
+            threshold,
+        }
+    }
+
+    /// Combines individual verdicts into a single list of categories
+    fn vote(&self, results: &[(ProviderResult, f64)]) -> Vec<ModerationCategories> {
+        let mut candidates: Vec<ModerationCategories> = results
+            .iter()
+            .flat_map(|(r, _)| r.categories.iter().cloned())
+            .collect();
+        candidates.sort();
+        candidates.dedup();
+
+        let total_weight: f64 = results.iter().map(|(_, w)| w).sum();
+        candidates
+            .into_iter()
+            .filter(|category| {
+                let voters = results
+                    .iter()
+                    .filter(|(r, _)| r.categories.contains(category));
+                match self.strategy {
+                    VotingStrategy::Union => true,
+                    VotingStrategy::Majority => voters.count() * 2 > results.len(),
+                    VotingStrategy::Weighted => {
+                        let weight: f64 = voters.map(|(_, w)| w).sum();
+                        weight / total_weight >= self.threshold
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+impl ModerationProvider for EnsembleModerationProvider {
+    async fn moderate(&self, document: &Document) -> Result<ModerationResponse, Errors> {
+        let responses = join_all(
+            self.members
+                .iter()
+                .map(|member| member.provider.moderate(document)),
+        )
+        .await;
+
+        let results: Vec<(ProviderResult, f64)> = responses
+            .into_iter()
+            .zip(self.members.iter())
+            .filter_map(|(response, member)| match response {
+                Ok(response) => Some((
+                    ProviderResult {
+                        provider: response.provider,
+                        categories: response.categories,
+                    },
+                    member.weight,
+                )),
+                Err(e) => {
+                    warn!(
+                        "Ensemble member failed to moderate, id={}, reason={:?}",
+                        document.id, e
+                    );
+                    metrics::MODERATION
+                        .with_label_values(&["ensemble_member_failed"])
+                        .inc();
+                    None
+                }
+            })
+            .collect();
+
+        if results.is_empty() {
+            error!("All ensemble members failed, id={}", document.id);
+            return Err(Errors::ModerationFailed);
+        }
+
+        let categories = self.vote(&results);
+        debug!(
+            "Ensemble verdict for id={}, strategy={:?}, categories={:?}",
+            document.id, self.strategy, categories
+        );
+        Ok(ModerationResponse {
+            categories,
+            provider: ModerationService::Ensemble,
+            provider_results: results.into_iter().map(|(r, _)| r).collect(),
+        })
+    }
+
+    fn supported_types(&self) -> Vec<SupportedMimeTypes> {
+        common_supported_types(self.members.iter().map(|m| m.provider.as_ref()))
+    }
+
+    fn max_document_size(&self) -> u64 {
+        self.members
+            .iter()
+            .map(|m| m.provider.max_document_size())
+            .min()
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::body::Bytes;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::moderation::tests::DummyModerationProvider;
+
+    const URL: &str = "http://localhost/image.png";
+
+    fn construct_document() -> Document {
+        Document {
+            id: Uuid::new_v4(),
+            content_type: "image/png".to_string(),
+            content_length: 100_u64,
+            bytes: Bytes::new(),
+            url: URL.to_string(),
+        }
+    }
+
+    fn construct_provider(
+        verdicts: Vec<(Vec<ModerationCategories>, f64)>,
+        strategy: VotingStrategy,
+        threshold: f64,
+    ) -> EnsembleModerationProvider {
+        let providers: Vec<(Box<dyn ModerationProvider + Send + Sync>, f64)> = verdicts
+            .into_iter()
+            .map(|(categories, weight)| {
+                let mut provider = DummyModerationProvider::with_provider(ModerationService::Aws);
+                provider.set(URL, categories);
+                let provider: Box<dyn ModerationProvider + Send + Sync> = Box::new(provider);
+                (provider, weight)
+            })
+            .collect();
+        EnsembleModerationProvider::from_providers(providers, strategy, threshold)
+    }
+
+    fn verdicts() -> Vec<(Vec<ModerationCategories>, f64)> {
+        vec![
+            (
+                vec![ModerationCategories::Drugs, ModerationCategories::Violence],
+                3_f64,
+            ),
+            (vec![ModerationCategories::Drugs], 1_f64),
+            (vec![ModerationCategories::Gambling], 1_f64),
+        ]
+    }
+
+    #[tokio::test]
+    async fn test_ensemble_union() {
+        let provider = construct_provider(verdicts(), VotingStrategy::Union, 0.5_f64);
+        let response = provider.moderate(&construct_document()).await.unwrap();
+        assert_eq!(response.provider, ModerationService::Ensemble);
+        assert_eq!(response.provider_results.len(), 3);
+        assert_eq!(
+            response.categories,
+            vec![
+                ModerationCategories::Violence,
+                ModerationCategories::Drugs,
+                ModerationCategories::Gambling
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ensemble_majority() {
+        let provider = construct_provider(verdicts(), VotingStrategy::Majority, 0.5_f64);
+        let response = provider.moderate(&construct_document()).await.unwrap();
+        assert_eq!(response.categories, vec![ModerationCategories::Drugs]);
+        assert_eq!(
+            response.provider_results[2].categories,
+            vec![ModerationCategories::Gambling]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ensemble_weighted() {
+        let provider = construct_provider(verdicts(), VotingStrategy::Weighted, 0.6_f64);
+        let response = provider.moderate(&construct_document()).await.unwrap();
+        assert_eq!(
+            response.categories,
+            vec![ModerationCategories::Violence, ModerationCategories::Drugs]
+        );
+    }
+
+    #[test]
+    fn test_ensemble_limits() {
+        let provider = construct_provider(verdicts(), VotingStrategy::Union, 0.5_f64);
+        assert_eq!(provider.max_document_size(), 65536);
+        assert_eq!(provider.supported_types().len(), 2);
+    }
+}
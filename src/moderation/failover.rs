@@ -0,0 +1,283 @@
+use async_trait::async_trait;
+use log::{error, info, warn};
+
+use crate::{
+    circuit_breaker::{CircuitBreaker, CircuitBreakerConfig},
+    document::Document,
+    metrics,
+    rpc::error::Errors,
+};
+
+use super::{common_supported_types, ModerationProvider, ModerationResponse, SupportedMimeTypes};
+
+struct GuardedProvider {
+    name: String,
+    provider: Box<dyn ModerationProvider + Send + Sync>,
+    breaker: CircuitBreaker,
+}
+
+impl GuardedProvider {
+    fn report_state(&self) {
+        metrics::MODERATION_BREAKER_STATE
+            .with_label_values(&[self.name.as_str()])
+            .set(self.breaker.state().as_i64());
+    }
+}
+
+/// A moderation provider which guards each of its providers with a circuit
+/// breaker and falls through to the next provider when one fails or has
+/// its breaker open. Providers are tried in the order given. Errors caused
+/// by the document are returned as is, another provider would reject the
+/// document as well.
+pub struct FailoverModerationProvider {
+    providers: Vec<GuardedProvider>,
+}
+
+impl FailoverModerationProvider {
+    /// Takes a list of named providers, in order of preference
+    pub fn new(
+        providers: Vec<(String, Box<dyn ModerationProvider + Send + Sync>)>,
+        breaker_config: &CircuitBreakerConfig,
+    ) -> Self {
+        let providers: Vec<GuardedProvider> = providers
+            .into_iter()
+            .map(|(name, provider)| GuardedProvider {
+                name,
+                provider,
+                breaker: CircuitBreaker::new(breaker_config),
+            })
+            .collect();
+        providers.iter().for_each(|p| p.report_state());
+        FailoverModerationProvider { providers }
+    }
+}
+
+/// Whether the error is caused by the document rather than the provider
+fn is_document_error(e: &Errors) -> bool {
+    matches!(
+        e,
+        Errors::UnsupportedImageType
+            | Errors::DocumentTooLarge
+            | Errors::ImageTooLarge
+            | Errors::ImageResizeError
+            | Errors::InvalidSvg
+    )
+}
+
+/// Whether the error means the provider is unhealthy, i.e. it could not be
+/// reached, throttled the request or failed with a server error
+fn is_provider_failure(e: &Errors) -> bool {
+    matches!(e, Errors::ModerationFailed | Errors::TimedOut)
+}
+
+#[async_trait]
+impl ModerationProvider for FailoverModerationProvider {
+    async fn moderate(&self, document: &Document) -> Result<ModerationResponse, Errors> {
+        for (index, guarded) in self.providers.iter().enumerate() {
+            if !guarded.breaker.allow() {
+                warn!(
+                    "Circuit breaker open, skipping moderation provider, id={}, provider={}",
+                    document.id, guarded.name
+                );
+                metrics::MODERATION
+                    .with_label_values(&["breaker_skipped"])
+                    .inc();
+                continue;
+            }
+
+            if index > 0 {
+                info!(
+                    "Failing over to moderation provider, id={}, provider={}",
+                    document.id, guarded.name
+                );
+                metrics::MODERATION.with_label_values(&["failover"]).inc();
+            }
+
+            let result = guarded.provider.moderate(document).await;
+            let failed_over = match &result {
+                Ok(_) => {
+                    guarded.breaker.record_success();
+                    false
+                }
+                Err(e) if is_document_error(e) => {
+                    warn!(
+                        "Document rejected by moderation provider, id={}, provider={}, reason={:?}",
+                        document.id, guarded.name, e
+                    );
+                    guarded.breaker.record_success();
+                    false
+                }
+                Err(e) => {
+                    warn!(
+                        "Moderation provider failed, id={}, provider={}, reason={:?}",
+                        document.id, guarded.name, e
+                    );
+                    if is_provider_failure(e) {
+                        guarded.breaker.record_failure();
+                    }
+                    true
+                }
+            };
+            guarded.report_state();
+
+            if !failed_over {
+                return result;
+            }
+        }
+
+        error!("No moderation provider available, id={}", document.id);
+        Err(Errors::ModerationFailed)
+    }
+
+    fn supported_types(&self) -> Vec<SupportedMimeTypes> {
+        common_supported_types(self.providers.iter().map(|p| p.provider.as_ref()))
+    }
+
+    fn max_document_size(&self) -> u64 {
+        self.providers
+            .iter()
+            .map(|p| p.provider.max_document_size())
+            .min()
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+
+    use hyper::body::Bytes;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::moderation::{tests::DummyModerationProvider, ModerationService};
+
+    struct FailingModerationProvider {
+        calls: Arc<AtomicU32>,
+        error: fn() -> Errors,
+    }
+
+    impl FailingModerationProvider {
+        fn new(calls: Arc<AtomicU32>) -> Self {
+            FailingModerationProvider {
+                calls,
+                error: || Errors::ModerationFailed,
+            }
+        }
+    }
+
+    #[async_trait]
+    impl ModerationProvider for FailingModerationProvider {
+        async fn moderate(&self, _: &Document) -> Result<ModerationResponse, Errors> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Err((self.error)())
+        }
+
+        fn supported_types(&self) -> Vec<SupportedMimeTypes> {
+            vec![SupportedMimeTypes::ImagePng]
+        }
+
+        fn max_document_size(&self) -> u64 {
+            1024_u64
+        }
+    }
+
+    fn construct_document() -> Document {
+        Document {
+            id: Uuid::new_v4(),
+            content_type: "image/png".to_string(),
+            content_length: 100_u64,
+            bytes: Bytes::new(),
+            url: "http://localhost/image.png".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failover_with_breaker() {
+        let calls = Arc::new(AtomicU32::new(0));
+        let primary: Box<dyn ModerationProvider + Send + Sync> =
+            Box::new(FailingModerationProvider::new(calls.clone()));
+        let secondary: Box<dyn ModerationProvider + Send + Sync> = Box::new(
+            DummyModerationProvider::with_provider(ModerationService::Aws),
+        );
+        let provider = FailoverModerationProvider::new(
+            vec![
+                ("test_primary".to_string(), primary),
+                ("test_secondary".to_string(), secondary),
+            ],
+            &CircuitBreakerConfig {
+                failure_threshold: 2,
+                cooldown: 3600,
+            },
+        );
+
+        assert_eq!(provider.max_document_size(), 1024);
+        assert!(provider.supported_types() == vec![SupportedMimeTypes::ImagePng]);
+
+        let document = construct_document();
+        for _ in 0..4 {
+            let response = provider.moderate(&document).await;
+            assert!(response.is_ok());
+            assert_eq!(response.unwrap().provider, ModerationService::Aws);
+        }
+
+        // The primary is no longer called once its breaker opens
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_failover_all_failed() {
+        let only: Box<dyn ModerationProvider + Send + Sync> =
+            Box::new(FailingModerationProvider::new(Arc::new(AtomicU32::new(0))));
+        let provider = FailoverModerationProvider::new(
+            vec![("test_only".to_string(), only)],
+            &CircuitBreakerConfig::default(),
+        );
+        let response = provider.moderate(&construct_document()).await;
+        assert_eq!(response.err().unwrap(), Errors::ModerationFailed);
+    }
+
+    #[tokio::test]
+    async fn test_failover_error_kinds() {
+        let construct_provider = |calls: Arc<AtomicU32>, error: fn() -> Errors| {
+            let primary: Box<dyn ModerationProvider + Send + Sync> =
+                Box::new(FailingModerationProvider { calls, error });
+            let secondary: Box<dyn ModerationProvider + Send + Sync> = Box::new(
+                DummyModerationProvider::with_provider(ModerationService::Aws),
+            );
+            FailoverModerationProvider::new(
+                vec![
+                    ("test_primary".to_string(), primary),
+                    ("test_secondary".to_string(), secondary),
+                ],
+                &CircuitBreakerConfig {
+                    failure_threshold: 1,
+                    cooldown: 3600,
+                },
+            )
+        };
+        let document = construct_document();
+
+        // Document errors are returned without failing over or opening the
+        // breaker
+        let calls = Arc::new(AtomicU32::new(0));
+        let provider = construct_provider(calls.clone(), || Errors::DocumentTooLarge);
+        for _ in 0..2 {
+            let response = provider.moderate(&document).await;
+            assert_eq!(response.err(), Some(Errors::DocumentTooLarge));
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // Rejected requests fail over without opening the breaker
+        let calls = Arc::new(AtomicU32::new(0));
+        let provider = construct_provider(calls.clone(), || Errors::ModerationRejected);
+        for _ in 0..2 {
+            let response = provider.moderate(&document).await.unwrap();
+            assert_eq!(response.provider, ModerationService::Aws);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}
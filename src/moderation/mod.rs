@@ -1,7 +1,19 @@
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 
-use crate::{aws::Rekognition, config::Configuration, document::Document, rpc::error::Errors};
+use crate::{
+    aws::Rekognition,
+    config::{AwsConfig, Configuration},
+    document::Document,
+    rpc::error::Errors,
+};
+
+use self::{ensemble::EnsembleModerationProvider, failover::FailoverModerationProvider};
+
+pub mod ensemble;
+pub mod failover;
+
+type GenericError = Box<dyn std::error::Error + Send + Sync>;
 
 #[derive(PartialEq, Eq)]
 pub enum SupportedMimeTypes {
@@ -10,6 +22,10 @@ pub enum SupportedMimeTypes {
     ImageGif,
     ImageBmp,
     ImageTiff,
+    ImageSvg,
+    ImageWebp,
+    #[cfg(feature = "avif")]
+    ImageAvif,
     Unsupported,
 }
 
@@ -23,6 +39,10 @@ impl SupportedMimeTypes {
             "image/gif" => SupportedMimeTypes::ImageGif,
             "image/bmp" => SupportedMimeTypes::ImageBmp,
             "image/x-ms-bmp" => SupportedMimeTypes::ImageBmp,
+            "image/svg+xml" => SupportedMimeTypes::ImageSvg,
+            "image/webp" => SupportedMimeTypes::ImageWebp,
+            #[cfg(feature = "avif")]
+            "image/avif" => SupportedMimeTypes::ImageAvif,
             _ => SupportedMimeTypes::Unsupported,
         }
     }
@@ -52,10 +72,20 @@ impl std::fmt::Display for ModerationCategories {
     }
 }
 
+/// The verdict of a single provider, kept alongside a combined verdict
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ProviderResult {
+    pub provider: ModerationService,
+    pub categories: Vec<ModerationCategories>,
+}
+
 #[derive(Clone)]
 pub struct ModerationResponse {
     pub categories: Vec<ModerationCategories>,
     pub provider: ModerationService,
+    /// Individual provider verdicts when the response was combined from
+    /// several providers. Empty for single providers.
+    pub provider_results: Vec<ProviderResult>,
 }
 
 /// A trait that all moderation services must implement
@@ -66,22 +96,95 @@ pub trait ModerationProvider: Send + Sync {
     fn max_document_size(&self) -> u64;
 }
 
+/// Mime types supported by every one of the given providers
+pub fn common_supported_types<'a>(
+    mut providers: impl Iterator<Item = &'a (dyn ModerationProvider + Send + Sync)>,
+) -> Vec<SupportedMimeTypes> {
+    let first = providers
+        .next()
+        .map(|p| p.supported_types())
+        .unwrap_or_default();
+    providers.fold(first, |acc, p| {
+        let supported = p.supported_types();
+        acc.into_iter().filter(|t| supported.contains(t)).collect()
+    })
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub enum ModerationService {
     Aws,
-    None,    // Used for empty api results only
-    Unknown, // Used when db was a provider value which does not appear here
+    Ensemble,
+    ImageHash, // Used when a result came from the image hash list
+    HashList,  // Used when a document matched the industry hash list
+    None,      // Used for empty api results only
+    Unknown,   // Used when db was a provider value which does not appear here
+}
+
+impl std::fmt::Display for ModerationService {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
 }
 
 impl ModerationService {
-    pub fn get_provider(
+    pub async fn get_provider(
+        config: &Configuration,
+    ) -> Result<Box<dyn ModerationProvider + Send + Sync>, GenericError> {
+        let moderation_config = &config.moderation;
+        let primary = Self::get_primary_provider(config).await?;
+        if moderation_config.fallback.is_none() && moderation_config.circuit_breaker.is_none() {
+            return Ok(primary);
+        }
+
+        let mut providers = vec![(format!("primary_{}", moderation_config.provider), primary)];
+        if let Some(fallback) = &moderation_config.fallback {
+            let aws = fallback.aws.as_ref().or(moderation_config.aws.as_ref());
+            providers.push((
+                format!("fallback_{}", fallback.provider),
+                fallback.provider.build_provider(aws).await?,
+            ));
+        }
+        let breaker_config = moderation_config
+            .circuit_breaker
+            .clone()
+            .unwrap_or_default();
+        Ok(Box::new(FailoverModerationProvider::new(
+            providers,
+            &breaker_config,
+        )))
+    }
+
+    async fn get_primary_provider(
         config: &Configuration,
-    ) -> Result<Box<dyn ModerationProvider + Send + Sync>, Box<dyn std::error::Error + Send + Sync>>
-    {
+    ) -> Result<Box<dyn ModerationProvider + Send + Sync>, GenericError> {
         match config.moderation.provider {
-            ModerationService::Aws => match &config.moderation.aws {
+            ModerationService::Ensemble => match &config.moderation.ensemble {
+                Some(ensemble_config) => {
+                    let s = EnsembleModerationProvider::new(ensemble_config, &config.moderation)
+                        .await?;
+                    Ok(Box::new(s))
+                }
+                None => Err("Ensemble moderation configuration is missing".into()),
+            },
+            _ => {
+                config
+                    .moderation
+                    .provider
+                    .build_provider(config.moderation.aws.as_ref())
+                    .await
+            }
+        }
+    }
+
+    /// Constructs a single, non composite, moderation provider
+    pub async fn build_provider(
+        &self,
+        aws: Option<&AwsConfig>,
+    ) -> Result<Box<dyn ModerationProvider + Send + Sync>, GenericError> {
+        match self {
+            ModerationService::Aws => match aws {
                 Some(aws_config) => {
-                    let s = Rekognition::new(&aws_config.region)?;
+                    let s = Rekognition::new(aws_config).await?;
                     Ok(Box::new(s))
                 }
                 None => Err("Moderation provider configuration is missing".into()),
@@ -99,10 +202,13 @@ pub mod tests {
     use hyper::body::Bytes;
     use uuid::Uuid;
 
+    use crate::utils::sha256;
+
     use super::*;
 
     pub struct DummyModerationProvider {
         store: Mutex<HashMap<String, Vec<ModerationCategories>>>,
+        provider: ModerationService,
     }
 
     impl Default for DummyModerationProvider {
@@ -115,6 +221,15 @@ pub mod tests {
         pub fn new() -> Self {
             DummyModerationProvider {
                 store: Mutex::new(HashMap::new()),
+                provider: ModerationService::None,
+            }
+        }
+
+        /// Creates a dummy provider which reports itself as the given service
+        pub fn with_provider(provider: ModerationService) -> Self {
+            DummyModerationProvider {
+                store: Mutex::new(HashMap::new()),
+                provider,
             }
         }
 
@@ -122,6 +237,12 @@ pub mod tests {
             let mut store = self.store.lock().unwrap();
             store.insert(url.to_string(), categories);
         }
+
+        /// Flags documents with the given contents, whatever their url
+        pub fn set_document(&mut self, bytes: &[u8], categories: Vec<ModerationCategories>) {
+            let mut store = self.store.lock().unwrap();
+            store.insert(sha256(bytes), categories);
+        }
     }
 
     #[async_trait]
@@ -130,10 +251,14 @@ pub mod tests {
             let url = &document.url;
             let store = self.store.lock().unwrap();
             let default = &Vec::<ModerationCategories>::new();
-            let categories = store.get(url).unwrap_or(default);
+            let categories = store
+                .get(&sha256(&document.bytes))
+                .or_else(|| store.get(url))
+                .unwrap_or(default);
             Ok(ModerationResponse {
                 categories: categories.clone(),
-                provider: ModerationService::None,
+                provider: self.provider.clone(),
+                provider_results: vec![],
             })
         }
 
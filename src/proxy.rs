@@ -2,10 +2,18 @@ extern crate bb8_postgres;
 extern crate tokio_postgres;
 
 use crate::cache::{get_cache, Cache};
-use crate::config::{Cors, SecurityConfig};
+use crate::config::{
+    AnimationConfig, Cors, MetadataConfig, PerceptualHashConfig, PlaceholderConfig, SecurityConfig,
+    SvgConfig,
+};
 use crate::db::{DatabaseFactory, DatabaseProvider, DbModerationRow};
-use crate::dns::StandardDnsResolver;
+use crate::dns::{DnsResolver, StandardDnsResolver};
+use crate::document::{ImageLimits, ResizeConfig};
+use crate::hashlist::HashList;
+use crate::image_pool::ImagePool;
 
+use crate::http::filters::destination::DestinationFilter;
+use crate::http::filters::domain::DomainFilter;
 use crate::http::filters::private_network::PrivateNetworkFilter;
 use crate::http::filters::UriFilter;
 use crate::http::{HttpClientFactory, HttpClientWrapper};
@@ -20,7 +28,9 @@ use crate::{built_info, rpc::error::Errors};
 use crate::{
     config::Configuration,
     rpc::{
-        requests::{DescribeRequest, FetchRequest, MethodHeader, ReportRequest, RpcMethods},
+        requests::{
+            DescribeRequest, FetchRequest, MethodHeader, OutputFormat, ReportRequest, RpcMethods,
+        },
         responses::Info,
     },
 };
@@ -28,7 +38,7 @@ use crate::{
 use chrono::Utc;
 use http_body_util::{BodyExt, Full};
 use hyper::body::{Body, Bytes};
-use hyper::header::HeaderValue;
+use hyper::header::{HeaderValue, ACCEPT};
 use hyper::{HeaderMap, Method, Request, Response, StatusCode};
 
 use log::{debug, error};
@@ -36,6 +46,7 @@ use moka::sync::Cache as MokaCache;
 use prometheus::Encoder;
 use serde::de;
 use std::sync::Arc;
+use std::time::Duration;
 use uuid::Uuid;
 type GenericError = Box<dyn std::error::Error + Send + Sync>;
 
@@ -45,29 +56,58 @@ pub struct Context {
     pub http_client_provider: HttpClientWrapper,
     pub cache: Option<Box<dyn Cache + Send + Sync>>,
     pub db_cache: Arc<MokaCache<String, DbModerationRow>>,
+    pub perceptual_hash: Option<PerceptualHashConfig>,
+    pub hash_list: Option<Arc<HashList>>,
+    pub animation: Option<AnimationConfig>,
+    pub svg: Option<SvgConfig>,
+    pub placeholder: Option<PlaceholderConfig>,
+    pub metadata: Option<MetadataConfig>,
+    pub resize: ResizeConfig,
+    pub image_pool: ImagePool,
+    pub image_limits: ImageLimits,
 }
 
 impl Context {
     pub async fn new(config: Arc<Configuration>) -> Result<Context, GenericError> {
         let database = DatabaseFactory::get_provider(&config.database).await?;
-        let moderation_provider = ModerationService::get_provider(&config)?;
-        let dns_resolver = StandardDnsResolver {};
-        //TODO: Add more filters here
-        let uri_filters: Vec<Box<dyn UriFilter + Send + Sync>> =
-            vec![Box::new(PrivateNetworkFilter::new(Box::new(dns_resolver)))];
-        let http_client = HttpClientFactory::get_provider(
-            config.ipfs.clone(),
-            config.max_document_size,
-            uri_filters,
-            config.timeout,
-            config.client_useragent.clone(),
-        );
+        let moderation_provider = ModerationService::get_provider(&config).await?;
+        let hash_list = match &config.hash_list {
+            Some(hash_list_config) => Some(Arc::new(HashList::load(hash_list_config)?)),
+            None => None,
+        };
+        // One resolver, and its cache, is shared by the filters and the connector
+        let dns_resolver: Arc<dyn DnsResolver + Send + Sync> = Arc::new(StandardDnsResolver::new(
+            &config.dns.clone().unwrap_or_default(),
+        )?);
+        let mut uri_filters: Vec<Box<dyn UriFilter + Send + Sync>> =
+            vec![Box::new(DestinationFilter::new(
+                &config.destination_filter.clone().unwrap_or_default(),
+                &config.ipfs,
+            ))];
+        if let Some(domain_filter_config) = &config.domain_filter {
+            let domain_filter = DomainFilter::new(domain_filter_config);
+            if let Some(interval) = domain_filter_config.reload_interval.filter(|i| *i > 0) {
+                domain_filter.watch(Duration::from_secs(interval));
+            }
+            uri_filters.push(Box::new(domain_filter));
+        }
+        uri_filters.push(Box::new(PrivateNetworkFilter::new(dns_resolver.clone())));
+        let http_client = HttpClientFactory::get_provider(&config, uri_filters, dns_resolver);
         Ok(Context {
             database,
             moderation_provider,
             http_client_provider: http_client,
             cache: get_cache(&config.cache_config),
             db_cache: Arc::new(MokaCache::new(10000)),
+            perceptual_hash: config.perceptual_hash.clone(),
+            hash_list,
+            animation: config.animation.clone(),
+            svg: config.svg.clone(),
+            placeholder: config.placeholder.clone(),
+            metadata: config.metadata.clone(),
+            resize: config.resize.clone().unwrap_or_default(),
+            image_pool: ImagePool::new(&config.image_pool.clone().unwrap_or_default()),
+            image_limits: config.image_limits.clone().unwrap_or_default(),
         })
     }
 }
@@ -218,6 +258,12 @@ async fn rpc(
         return Err(Errors::RpcPayloadTooBigError);
     }
 
+    let accept = req
+        .headers()
+        .get(ACCEPT)
+        .and_then(|h| h.to_str().ok())
+        .map(String::from);
+
     match req.collect().await.map(|r| r.to_bytes()) {
         Ok(body) => match decode::<MethodHeader>(&body) {
             Ok(header) if header.jsonrpc.eq_ignore_ascii_case(VERSION) => {
@@ -227,13 +273,19 @@ async fn rpc(
                     .inc();
                 match method {
                     RpcMethods::img_proxy_fetch => {
-                        let params = decode::<FetchRequest>(&body)?;
+                        let mut params = decode::<FetchRequest>(&body)?;
+                        if params.params.format == Some(OutputFormat::Auto) {
+                            params.params.format =
+                                accept.as_deref().and_then(OutputFormat::from_accept);
+                        }
                         let result = fetch(ctx, &req_id, &params.params).await?;
                         Ok(FetchResponse::to_response(
                             &params.params.response_type,
                             result.document,
                             result.moderation_status,
                             result.categories,
+                            result.blurhash,
+                            result.image_info,
                             &req_id,
                         ))
                     }
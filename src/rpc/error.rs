@@ -30,6 +30,15 @@ pub enum Errors {
     InvalidOrBlockedHost,
     TimedOut,
     ImageResizeError,
+    HashListMatch,
+    InvalidSvg,
+    ImageTooLarge,
+    InvalidTransform,
+    DocumentTooLarge,
+    TooManyRedirects,
+    HostBusy,
+    HostUnavailable,
+    ModerationRejected,
 }
 
 impl Errors {
@@ -54,6 +63,32 @@ impl Errors {
             ),
             Errors::ImageResizeError => (112, "Image Resize Error".to_string()),
             Errors::RpcPayloadTooBigError => (113, "RPC Payload too big".to_string()),
+            Errors::HashListMatch => (114, "Image matched a known hash list".to_string()),
+            Errors::InvalidSvg => (115, "Invalid or unsafe SVG document".to_string()),
+            Errors::ImageTooLarge => (
+                116,
+                "Image dimensions exceed the configured limits".to_string(),
+            ),
+            Errors::InvalidTransform => {
+                (117, "Invalid image transformation parameters".to_string())
+            }
+            Errors::DocumentTooLarge => (
+                118,
+                "Document exceeds the maximum document size".to_string(),
+            ),
+            Errors::TooManyRedirects => (119, "Too many redirects".to_string()),
+            Errors::HostBusy => (
+                120,
+                "Too many requests in flight to the destination host".to_string(),
+            ),
+            Errors::HostUnavailable => (
+                121,
+                "Destination host is failing and temporarily skipped".to_string(),
+            ),
+            Errors::ModerationRejected => (
+                122,
+                "Moderation request rejected by the provider".to_string(),
+            ),
         };
 
         RpcError {
@@ -4,14 +4,22 @@ pub mod responses;
 
 use std::sync::Arc;
 
-use log::{debug, error, info};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use futures::FutureExt;
+use image::DynamicImage;
+use log::{debug, error, info, warn};
 use uuid::Uuid;
 
-use crate::document::Document;
+use crate::config::{AnimationMode, PlaceholderConfig, PlaceholderMode};
+use crate::db::ImageHashAction;
+use crate::document::{Document, ImageInfo, ImageTransform};
 use crate::utils::sha256;
 use crate::{
     metrics,
-    moderation::{ModerationService, SupportedMimeTypes},
+    moderation::{
+        ModerationCategories, ModerationResponse, ModerationService, ProviderResult,
+        SupportedMimeTypes,
+    },
     proxy::Context,
     rpc::error::Errors,
 };
@@ -22,6 +30,9 @@ use responses::*;
 /// rpc version information
 pub static VERSION: &str = "1.0.0";
 
+// Frames of an animation moderated at once
+const MAX_CONCURRENT_FRAMES: usize = 4;
+
 async fn fetch_document(
     ctx: Arc<Context>,
     req_id: &Uuid,
@@ -37,11 +48,29 @@ async fn fetch_document(
         Ok(doc)
     } else {
         let document = Arc::new(ctx.http_client_provider.fetch(req_id, url).await?);
+        check_hash_list(&ctx, req_id, url, &document).await?;
         if SupportedMimeTypes::from_string(&document.content_type)
             == SupportedMimeTypes::Unsupported
         {
             Err(Errors::UnsupportedImageType)
         } else {
+            // Images whose header declares excessive dimensions are never decoded
+            if let Err(Errors::ImageTooLarge) = document.check_limits(&ctx.image_limits) {
+                return Err(Errors::ImageTooLarge);
+            }
+            let document = if document.is_svg() {
+                Arc::new(prepare_svg(&ctx, req_id, document).await?)
+            } else if ctx.metadata.as_ref().is_some_and(|c| c.strip) {
+                // Images that cannot be parsed are served as fetched
+                let job_document = document.clone();
+                ctx.image_pool
+                    .run(move || Ok(job_document.strip_metadata()))
+                    .await?
+                    .map(Arc::new)
+                    .unwrap_or(document)
+            } else {
+                document
+            };
             if let Some(cache) = &ctx.cache {
                 debug!("Inserted document into cache, url:{}", url);
                 cache.put(&cache_key, &document);
@@ -51,10 +80,494 @@ async fn fetch_document(
     }
 }
 
+/// Sanitizes a fetched SVG so that it is safe to serve, replacing it with a
+/// PNG rendering if configured to do so
+async fn prepare_svg(
+    ctx: &Context,
+    req_id: &Uuid,
+    document: Arc<Document>,
+) -> Result<Document, Errors> {
+    metrics::DOCUMENT.with_label_values(&["svg"]).inc();
+    let serve_raster = ctx.svg.as_ref().map(|c| c.serve_raster).unwrap_or(false);
+    if serve_raster {
+        debug!("Serving rasterized svg, id={}", req_id);
+    }
+    let limits = ctx.image_limits.clone();
+    ctx.image_pool
+        .run(move || {
+            let sanitized = document.sanitize_svg()?;
+            if serve_raster {
+                sanitized.rasterize_svg(&limits)
+            } else {
+                Ok(sanitized)
+            }
+        })
+        .await
+}
+
+/// Matches a freshly fetched document against the industry hash list.
+/// Matches are audited, recorded as blocked and reported as an error so
+/// that the document is never cached, moderated or served.
+async fn check_hash_list(
+    ctx: &Context,
+    req_id: &Uuid,
+    url: &str,
+    document: &Arc<Document>,
+) -> Result<(), Errors> {
+    let hash_list = match &ctx.hash_list {
+        Some(hash_list) => hash_list.clone(),
+        None => return Ok(()),
+    };
+    let job_document = document.clone();
+    let limits = ctx.image_limits.clone();
+    let hash_list_match = match ctx
+        .image_pool
+        .run(move || Ok(hash_list.check(&job_document, &limits)))
+        .await?
+    {
+        Some(hash_list_match) => hash_list_match,
+        None => return Ok(()),
+    };
+
+    warn!(
+        "Document matched hash list, id={}, match_type={}, entry={}, distance={}, url={}",
+        req_id, hash_list_match.match_type, hash_list_match.entry, hash_list_match.distance, url
+    );
+    metrics::HASH_LIST_MATCHES
+        .with_label_values(&[hash_list_match.match_type.to_string().as_str()])
+        .inc();
+    metrics::DOCUMENT
+        .with_label_values(&["hash_list_blocked"])
+        .inc();
+
+    let doc_hash = sha256(&document.bytes);
+    if let Err(e) = ctx
+        .database
+        .add_hash_list_audit(req_id, url, &doc_hash, &hash_list_match)
+        .await
+    {
+        error!(
+            "Hash list audit not recorded for id={}, reason={}",
+            req_id, e
+        );
+    }
+
+    // Insert a verdict for new urls and overwrite any earlier one for known urls
+    let recorded = match ctx
+        .database
+        .add_moderation_result(url, ModerationService::HashList, true, &[], &[], None)
+        .await
+    {
+        Ok(_) => {
+            ctx.database
+                .update_moderation_result(url, ModerationService::HashList, true, &[], &[], None)
+                .await
+        }
+        Err(e) => Err(e),
+    };
+    if let Err(e) = recorded {
+        error!("Database not updated for id={}, reason={}", req_id, e);
+    }
+    ctx.db_cache.invalidate(url);
+
+    Err(Errors::HashListMatch)
+}
+
+/// Checks the perceptual hash of the document against the image hash list.
+/// Returns a moderation response if a matching entry was found, in which
+/// case the moderation provider does not need to be consulted. `Block`
+/// entries take precedence, `Allow` entries only match within the much
+/// stricter `max_allow_distance` as near collisions are cheap to craft.
+async fn match_image_hash(
+    ctx: &Context,
+    req_id: &Uuid,
+    image: &Arc<DynamicImage>,
+) -> Option<ModerationResponse> {
+    let config = ctx.perceptual_hash.as_ref()?;
+    let job_image = image.clone();
+    let hash = match ctx
+        .image_pool
+        .run(move || Ok(Document::difference_hash(&job_image)))
+        .await
+    {
+        Ok(hash) => hash,
+        Err(e) => {
+            warn!(
+                "Unable to compute perceptual hash, id={}, reason={:?}",
+                req_id, e
+            );
+            return None;
+        }
+    };
+
+    let rows = match ctx
+        .database
+        .find_image_hash(hash, config.max_distance)
+        .await
+    {
+        Ok(rows) => rows,
+        Err(e) => {
+            error!(
+                "Error querying image hashes for id={}, reason={}",
+                req_id, e
+            );
+            return None;
+        }
+    };
+
+    let max_allow_distance = config
+        .max_allow_distance
+        .unwrap_or(0)
+        .min(config.max_distance);
+    let row = rows
+        .iter()
+        .find(|row| row.action == ImageHashAction::Block)
+        .or_else(|| {
+            rows.iter().find(|row| {
+                row.action == ImageHashAction::Allow && row.distance <= max_allow_distance
+            })
+        });
+    row.map(|row| {
+        info!(
+            "Image hash list match, id={}, hash={:016x}, entry={:016x}, distance={}, action={:?}",
+            req_id, hash, row.hash, row.distance, row.action
+        );
+        let categories = match row.action {
+            ImageHashAction::Allow => {
+                metrics::MODERATION
+                    .with_label_values(&["image_hash_allowed"])
+                    .inc();
+                vec![]
+            }
+            ImageHashAction::Block if row.categories.is_empty() => {
+                metrics::MODERATION
+                    .with_label_values(&["image_hash_blocked"])
+                    .inc();
+                vec![ModerationCategories::Unknown]
+            }
+            ImageHashAction::Block => {
+                metrics::MODERATION
+                    .with_label_values(&["image_hash_blocked"])
+                    .inc();
+                row.categories.clone()
+            }
+        };
+        ModerationResponse {
+            categories,
+            provider: ModerationService::ImageHash,
+            provider_results: vec![],
+        }
+    })
+}
+
+/// Submits a single image to the moderation provider, resizing or converting
+/// it first if the provider cannot accept it as is.
+async fn moderate_image(
+    ctx: &Context,
+    req_id: &Uuid,
+    document: &Document,
+) -> Result<ModerationResponse, Errors> {
+    let max_document_size = ctx.moderation_provider.max_document_size();
+    let supported_types = ctx.moderation_provider.supported_types();
+    let document_type = SupportedMimeTypes::from_string(&document.content_type);
+
+    metrics::MODERATION.with_label_values(&["requests"]).inc();
+
+    info!("Submitting moderation request for id:{}", req_id);
+    // Resize the image if required or reformat to png if required
+    if document.bytes.len() as u64 >= max_document_size || !supported_types.contains(&document_type)
+    {
+        info!("Image resizing required, id={}", req_id);
+        let allow_jpeg = supported_types.contains(&SupportedMimeTypes::ImageJpeg);
+        let job_document = document.clone();
+        let config = ctx.resize.clone();
+        let limits = ctx.image_limits.clone();
+        let resized_doc = ctx
+            .image_pool
+            .run(move || job_document.resize_image(max_document_size, allow_jpeg, &config, &limits))
+            .await?;
+        ctx.moderation_provider.moderate(&resized_doc).await
+    } else {
+        ctx.moderation_provider.moderate(document).await
+    }
+}
+
+/// Moderates the sampled frames of an animated image. The verdict is the
+/// union of the categories found in any frame.
+async fn moderate_frames(
+    ctx: &Context,
+    req_id: &Uuid,
+    frames: Vec<Document>,
+) -> Result<ModerationResponse, Errors> {
+    metrics::MODERATION.with_label_values(&["animated"]).inc();
+    metrics::MODERATION
+        .with_label_values(&["animation_frames"])
+        .inc_by(frames.len() as u64);
+
+    // Each frame is moved into a boxed future, borrowing the frames from
+    // the stream makes the request future not `Send`
+    let responses: Vec<ModerationResponse> = stream::iter(
+        frames
+            .into_iter()
+            .map(|frame| async move { moderate_image(ctx, req_id, &frame).await }.boxed()),
+    )
+    .buffer_unordered(MAX_CONCURRENT_FRAMES)
+    .try_collect()
+    .await?;
+
+    let mut categories: Vec<ModerationCategories> = Vec::new();
+    let mut provider_results: Vec<ProviderResult> = Vec::new();
+    for response in &responses {
+        categories.extend(response.categories.iter().cloned());
+        for result in &response.provider_results {
+            match provider_results
+                .iter_mut()
+                .find(|r| r.provider == result.provider)
+            {
+                Some(existing) => existing
+                    .categories
+                    .extend(result.categories.iter().cloned()),
+                None => provider_results.push(result.clone()),
+            }
+        }
+    }
+    categories.sort();
+    categories.dedup();
+    provider_results.iter_mut().for_each(|r| {
+        r.categories.sort();
+        r.categories.dedup();
+    });
+    debug!(
+        "Animation verdict for id={}, frames={}, categories={:?}",
+        req_id,
+        responses.len(),
+        categories
+    );
+
+    Ok(ModerationResponse {
+        categories,
+        provider: responses
+            .first()
+            .map(|r| r.provider.clone())
+            .unwrap_or(ModerationService::Unknown),
+        provider_results,
+    })
+}
+
+/// Submits the document for moderation. Animated images are sampled frame
+/// by frame when configured, otherwise only their first frame is seen.
+/// Returns the number of frames of sampled animations along with the verdict.
+async fn moderate_document(
+    ctx: &Context,
+    req_id: &Uuid,
+    document: &Document,
+) -> Result<(ModerationResponse, Option<u32>), Errors> {
+    let frames = match &ctx.animation {
+        Some(config) => {
+            let max_frames = config.max_frames as usize;
+            let contact_sheet = config.mode == AnimationMode::ContactSheet;
+            let job_document = document.clone();
+            let limits = ctx.image_limits.clone();
+            ctx.image_pool
+                .run(move || job_document.frame_documents(max_frames, contact_sheet, &limits))
+                .await?
+        }
+        None => None,
+    };
+
+    let (mod_response, frame_count) = match frames {
+        Some((frames, frame_count)) => {
+            info!(
+                "Moderating animated image, id={}, frames={}, documents={}",
+                req_id,
+                frame_count,
+                frames.len()
+            );
+            (
+                moderate_frames(ctx, req_id, frames).await?,
+                Some(frame_count),
+            )
+        }
+        None => (moderate_image(ctx, req_id, document).await?, None),
+    };
+
+    metrics::TRAFFIC
+        .with_label_values(&["moderated"])
+        .inc_by(document.bytes.len() as u64);
+
+    Ok((mod_response, frame_count))
+}
+
+/// Decodes the fetched image once for hashing and describing it. Failures
+/// are logged, the image is then moderated without either.
+async fn decode_image(
+    ctx: &Context,
+    req_id: &Uuid,
+    document: &Document,
+) -> Option<Arc<DynamicImage>> {
+    let job_document = document.clone();
+    let limits = ctx.image_limits.clone();
+    match ctx
+        .image_pool
+        .run(move || job_document.load_image(&limits))
+        .await
+    {
+        Ok(image) => Some(Arc::new(image)),
+        Err(e) => {
+            warn!("Unable to decode image, id={}, reason={:?}", req_id, e);
+            None
+        }
+    }
+}
+
+/// Describes the fetched image for clients from its decoded image, reusing
+/// the frame count when known. Failures are logged and leave the result
+/// without image properties.
+async fn describe_image(
+    ctx: &Context,
+    req_id: &Uuid,
+    document: &Document,
+    image: Arc<DynamicImage>,
+    frames: Option<u32>,
+) -> Option<ImageInfo> {
+    let job_document = document.clone();
+    let limits = ctx.image_limits.clone();
+    match ctx
+        .image_pool
+        .run(move || job_document.image_info(&image, frames, &limits))
+        .await
+    {
+        Ok(image_info) => Some(image_info),
+        Err(e) => {
+            warn!("Unable to describe image, id={}, reason={:?}", req_id, e);
+            None
+        }
+    }
+}
+
+/// Rejects requested dimensions that are zero or beyond the image limits,
+/// boxes larger than the pixel limit, and qualities outside of 1 to 100
+fn validate_transform(ctx: &Context, params: &FetchRequestParams) -> Result<(), Errors> {
+    let width_valid = params
+        .width
+        .is_none_or(|w| w > 0 && w <= ctx.image_limits.max_width);
+    let height_valid = params
+        .height
+        .is_none_or(|h| h > 0 && h <= ctx.image_limits.max_height);
+    let pixels_valid = match (params.width, params.height) {
+        (Some(w), Some(h)) => w as u64 * h as u64 <= ctx.image_limits.max_pixels,
+        _ => true,
+    };
+    let quality_valid = params.quality.is_none_or(|q| (1..=100).contains(&q));
+    if width_valid && height_valid && pixels_valid && quality_valid {
+        Ok(())
+    } else {
+        Err(Errors::InvalidTransform)
+    }
+}
+
+/// Applies the client requested resizing and format conversion
+async fn transform_document(
+    ctx: &Context,
+    req_id: &Uuid,
+    params: &FetchRequestParams,
+    document: Arc<Document>,
+) -> Result<Document, Errors> {
+    debug!(
+        "Transforming document, id={}, width={:?}, height={:?}, fit={:?}, format={:?}, quality={:?}",
+        req_id, params.width, params.height, params.fit, params.format, params.quality
+    );
+    let transform = ImageTransform {
+        width: params.width,
+        height: params.height,
+        fit: params.fit.unwrap_or_default(),
+        format: params.format,
+        quality: params.quality,
+    };
+    let limits = ctx.image_limits.clone();
+    ctx.image_pool
+        .run(move || document.transform(&transform, &limits))
+        .await
+}
+
+/// Renders the configured placeholder for a blocked document, fetching it
+/// if it was blocked by an earlier moderation result. Returns either a
+/// placeholder document or a BlurHash, or neither if rendering fails.
+async fn render_placeholder(
+    ctx: &Arc<Context>,
+    req_id: &Uuid,
+    config: &PlaceholderConfig,
+    url: &str,
+    document: Option<Arc<Document>>,
+) -> (Option<Arc<Document>>, Option<String>) {
+    let document = match document {
+        Some(document) => document,
+        None => match fetch_document(ctx.clone(), req_id, url).await {
+            Ok(document) => document,
+            Err(e) => {
+                warn!(
+                    "Unable to fetch blocked document for placeholder, id={}, reason={:?}",
+                    req_id, e
+                );
+                return (None, None);
+            }
+        },
+    };
+
+    let mode = config.mode.clone();
+    let limits = ctx.image_limits.clone();
+    let result = ctx
+        .image_pool
+        .run(move || match mode {
+            PlaceholderMode::Blur => document.blurred(&limits).map(|d| (Some(Arc::new(d)), None)),
+            PlaceholderMode::Pixelate => document
+                .pixelated(&limits)
+                .map(|d| (Some(Arc::new(d)), None)),
+            PlaceholderMode::BlurHash => document.blurhash(&limits).map(|h| (None, Some(h))),
+        })
+        .await;
+    match result {
+        Ok(placeholder) => {
+            info!(
+                "Placeholder rendered for blocked document, id={}, mode={:?}",
+                req_id, config.mode
+            );
+            metrics::DOCUMENT.with_label_values(&["placeholder"]).inc();
+            placeholder
+        }
+        Err(e) => {
+            warn!(
+                "Unable to render placeholder, id={}, mode={:?}, reason={:?}",
+                req_id, config.mode, e
+            );
+            (None, None)
+        }
+    }
+}
+
 pub async fn fetch(
     ctx: Arc<Context>,
     req_id: &Uuid,
     params: &FetchRequestParams,
+) -> Result<ModerationResult, Errors> {
+    match fetch_and_moderate(ctx, req_id, params).await {
+        // Hash list matches are never served, regardless of the force flag
+        Err(Errors::HashListMatch) => Ok(ModerationResult {
+            moderation_status: ModerationStatus::Blocked,
+            categories: vec![],
+            data: String::default(),
+            document: None,
+            blurhash: None,
+            image_info: None,
+        }),
+        result => result,
+    }
+}
+
+async fn fetch_and_moderate(
+    ctx: Arc<Context>,
+    req_id: &Uuid,
+    params: &FetchRequestParams,
 ) -> Result<ModerationResult, Errors> {
     info!(
         "New fetch request, id={}, force={}, url={}",
@@ -65,6 +578,7 @@ pub async fn fetch(
         metrics::DOCUMENT.with_label_values(&["forced"]).inc();
         info!("Document id={} has forced flag enabled.", req_id);
     }
+    validate_transform(&ctx, params)?;
 
     let db_results = if let Some(result) = ctx.db_cache.get(&params.url) {
         info!(
@@ -95,7 +609,9 @@ pub async fn fetch(
         results
     };
 
-    let (moderation_status, categories, document) = match db_results.first() {
+    let (moderation_status, categories, document, placeholder, image_info) = match db_results
+        .first()
+    {
         Some(result) => {
             metrics::MODERATION.with_label_values(&["cache_hit"]).inc();
             info!(
@@ -113,33 +629,36 @@ pub async fn fetch(
                 metrics::DOCUMENT.with_label_values(&["blocked"]).inc();
                 None
             };
-            (result.blocked.into(), result.categories.clone(), document)
+            // Hash list matches are never rendered, not even as placeholders
+            let placeholder = ctx
+                .placeholder
+                .as_ref()
+                .filter(|_| result.provider != ModerationService::HashList);
+            (
+                result.blocked.into(),
+                result.categories.clone(),
+                document,
+                placeholder,
+                result.image_info.clone(),
+            )
         }
         None => {
             metrics::MODERATION.with_label_values(&["cache_miss"]).inc();
             info!("Database has no moderation results for id={}", req_id);
             let document = fetch_document(ctx.clone(), req_id, &params.url).await?;
-            let max_document_size = ctx.moderation_provider.max_document_size();
-            let supported_types = ctx.moderation_provider.supported_types();
-            let document_type = SupportedMimeTypes::from_string(&document.content_type);
-
-            metrics::MODERATION.with_label_values(&["requests"]).inc();
-
-            info!("Submitting moderation request for id:{}", req_id);
-            // Resize the image if required or reformat to png if required
-            let mod_response = if document.bytes.len() as u64 >= max_document_size
-                || !supported_types.contains(&document_type)
-            {
-                info!("Image resizing required, id={}", req_id);
-                let resized_doc = document.resize_image(max_document_size)?;
-                ctx.moderation_provider.moderate(&resized_doc).await?
-            } else {
-                ctx.moderation_provider.moderate(&document).await?
+            let image = decode_image(&ctx, req_id, &document).await;
+            let hash_response = match &image {
+                Some(image) => match_image_hash(&ctx, req_id, image).await,
+                None => None,
+            };
+            let (mod_response, frames) = match hash_response {
+                Some(response) => (response, None),
+                None => moderate_document(&ctx, req_id, &document).await?,
+            };
+            let image_info = match image {
+                Some(image) => describe_image(&ctx, req_id, &document, image, frames).await,
+                None => None,
             };
-
-            metrics::TRAFFIC
-                .with_label_values(&["moderated"])
-                .inc_by(document.bytes.len() as u64);
 
             mod_response.categories.iter().for_each(|c| {
                 metrics::MODERATION_CATEGORIES
@@ -150,7 +669,8 @@ pub async fn fetch(
             let blocked = !mod_response.categories.is_empty();
             let mod_status: ModerationStatus = blocked.into();
 
-            let document = if !blocked || params.force {
+            // Blocked documents are kept only to render a placeholder from
+            let document = if !blocked || params.force || ctx.placeholder.is_some() {
                 Some(document)
             } else {
                 None
@@ -168,6 +688,8 @@ pub async fn fetch(
                     mod_response.provider,
                     blocked,
                     &mod_response.categories,
+                    &mod_response.provider_results,
+                    image_info.as_ref(),
                 )
                 .await
             {
@@ -176,10 +698,30 @@ pub async fn fetch(
                     error!("Database not updated for id={}, reason={}", req_id, e)
                 }
             };
-            (mod_status, categories, document)
+            (
+                mod_status,
+                categories,
+                document,
+                ctx.placeholder.as_ref(),
+                image_info,
+            )
         }
     };
 
+    let blocked = moderation_status == ModerationStatus::Blocked && !params.force;
+    let (document, blurhash) = match (placeholder, document) {
+        (Some(config), document) if blocked => {
+            render_placeholder(&ctx, req_id, config, &params.url, document).await
+        }
+        (_, Some(document)) if params.has_transform() => (
+            Some(Arc::new(
+                transform_document(&ctx, req_id, params, document).await?,
+            )),
+            None,
+        ),
+        (_, document) => (document, None),
+    };
+
     //TODO: This section needs rework in version 2.0.0. See issue #83.
     let result = if params.force {
         ModerationResult {
@@ -187,6 +729,8 @@ pub async fn fetch(
             categories: vec![],
             data: String::default(), //TODO: This smells, refactor away without breaking API
             document,
+            blurhash: None,
+            image_info,
         }
     } else {
         ModerationResult {
@@ -194,6 +738,8 @@ pub async fn fetch(
             categories,
             data: String::default(), //TODO: This smells, refactor away without breaking API
             document,
+            blurhash,
+            image_info,
         }
     };
 
@@ -227,6 +773,7 @@ pub async fn describe(
                             status,
                             categories: res.categories.clone(),
                             provider: res.provider.clone(),
+                            image_info: res.image_info.clone(),
                         }
                     }
                     None => DescribeResult {
@@ -234,6 +781,7 @@ pub async fn describe(
                         status: DocumentStatus::NeverSeen,
                         categories: Vec::new(),
                         provider: ModerationService::None,
+                        image_info: None,
                     },
                 })
                 .collect();
@@ -289,19 +837,30 @@ pub async fn describe_report(
 #[cfg(test)]
 mod tests {
     use hyper::body::Bytes;
+    use image::codecs::gif::GifEncoder;
+    use image::{Delay, DynamicImage, Frame, ImageOutputFormat, Rgb, RgbImage, Rgba, RgbaImage};
     use moka::sync::Cache as MokaCache;
 
-    use crate::config::{Host, IpfsGatewayConfig};
+    use crate::config::{
+        AnimationConfig, Host, IpfsGatewayConfig, MetadataConfig, PerceptualHashConfig,
+    };
     use crate::db::tests::DummyDatabase;
     use crate::dns::DummyDnsResolver;
+    use crate::document::{ImageLimits, ResizeConfig};
+    use crate::hashlist::HashList;
     use crate::http::filters::private_network::PrivateNetworkFilter;
     use crate::http::filters::UriFilter;
+    use crate::http::origin::{OriginConfig, OriginGuard};
+    use crate::http::retry::{RetryConfig, RetryPolicy};
     use crate::http::tests::DummyHttpClient;
     use crate::http::HttpClientWrapper;
+    use crate::image_pool::{ImagePool, ImagePoolConfig};
     use crate::moderation::tests::DummyModerationProvider;
     use crate::moderation::ModerationCategories;
 
+    use std::io::Cursor;
     use std::net::IpAddr;
+    use std::time::Duration;
 
     use super::*;
 
@@ -313,6 +872,13 @@ mod tests {
         document: Option<Document>,
         categories: Option<Vec<ModerationCategories>>,
     ) -> Arc<Context> {
+        Arc::new(construct_raw_context(document, categories))
+    }
+
+    fn construct_raw_context(
+        document: Option<Document>,
+        categories: Option<Vec<ModerationCategories>>,
+    ) -> Context {
         let database = DummyDatabase::new();
         let mut moderation_provider = DummyModerationProvider::new();
         let mut http_client = DummyHttpClient::new();
@@ -331,7 +897,7 @@ mod tests {
             resolved_address: ip_vec,
         };
         let uri_filters: Vec<Box<dyn UriFilter + Send + Sync>> =
-            vec![Box::new(PrivateNetworkFilter::new(Box::new(dns_resolver)))];
+            vec![Box::new(PrivateNetworkFilter::new(Arc::new(dns_resolver)))];
 
         if let Some(doc) = document {
             let url = doc.url.clone();
@@ -341,27 +907,61 @@ mod tests {
             };
         }
 
-        let http_client_provider =
-            HttpClientWrapper::new(Box::new(http_client), ipfs_config, uri_filters);
+        let origins = OriginGuard::new(&OriginConfig::default(), &ipfs_config);
+        let http_client_provider = HttpClientWrapper::new(
+            Box::new(http_client),
+            ipfs_config,
+            uri_filters,
+            5,
+            origins,
+            RetryPolicy::new(&RetryConfig::default(), Duration::from_secs(10)),
+        );
 
-        let context = Context {
+        Context {
             database: Box::new(database),
             moderation_provider: Box::new(moderation_provider),
             http_client_provider,
             cache: None,
             db_cache: Arc::new(MokaCache::new(10)),
-        };
-
-        Arc::new(context)
+            perceptual_hash: None,
+            hash_list: None,
+            animation: None,
+            svg: None,
+            placeholder: None,
+            metadata: None,
+            resize: ResizeConfig::default(),
+            image_pool: ImagePool::new(&ImagePoolConfig::default()),
+            image_limits: ImageLimits::default(),
+        }
     }
 
     fn construct_document(url: &str) -> Document {
-        let buffer = "Hello There";
+        let mut cursor = Cursor::new(Vec::new());
+        DynamicImage::new_rgb8(8, 8)
+            .write_to(&mut cursor, ImageOutputFormat::Png)
+            .unwrap();
+        let bytes = cursor.into_inner();
         Document {
             id: Uuid::new_v4(),
             content_type: "image/png".to_string(),
-            content_length: buffer.len() as u64,
-            bytes: Bytes::from(buffer),
+            content_length: bytes.len() as u64,
+            bytes: Bytes::from(bytes),
+            url: url.to_string(),
+        }
+    }
+
+    fn construct_image_document(url: &str) -> Document {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(64, 64, |x, y| {
+            Rgb([(x * 4) as u8, (y * 4) as u8, 128])
+        }));
+        let mut cursor = Cursor::new(Vec::new());
+        image.write_to(&mut cursor, ImageOutputFormat::Png).unwrap();
+        let bytes = cursor.into_inner();
+        Document {
+            id: Uuid::new_v4(),
+            content_type: "image/png".to_string(),
+            content_length: bytes.len() as u64,
+            bytes: Bytes::from(bytes),
             url: url.to_string(),
         }
     }
@@ -389,6 +989,7 @@ mod tests {
             url: URL_SAFE_IMAGE.to_string(),
             force: false,
             response_type: ResponseType::Json,
+            ..Default::default()
         };
         let result = fetch(context, &Uuid::new_v4(), &params).await;
         assert!(result.is_ok());
@@ -396,6 +997,43 @@ mod tests {
         assert_eq!(result.moderation_status, ModerationStatus::Allowed);
     }
 
+    #[tokio::test]
+    async fn test_fetch_image_info() {
+        let doc = construct_document(URL_SAFE_IMAGE);
+        let size = doc.bytes.len() as u64;
+        let context = construct_context(Some(doc), None);
+
+        let params = FetchRequestParams {
+            url: URL_SAFE_IMAGE.to_string(),
+            force: false,
+            response_type: ResponseType::Json,
+            ..Default::default()
+        };
+        let result = fetch(context.clone(), &Uuid::new_v4(), &params)
+            .await
+            .unwrap();
+        let expected = ImageInfo {
+            width: 8,
+            height: 8,
+            format: "png".to_string(),
+            size,
+            frames: 1,
+            dominant_color: "#000000".to_string(),
+        };
+        assert_eq!(result.image_info, Some(expected.clone()));
+
+        // Later requests and descriptions use the recorded properties
+        let result = fetch(context.clone(), &Uuid::new_v4(), &params)
+            .await
+            .unwrap();
+        assert_eq!(result.image_info, Some(expected.clone()));
+        let params = DescribeRequestParams {
+            urls: vec![URL_SAFE_IMAGE.to_string()],
+        };
+        let result = describe(context, &Uuid::new_v4(), &params).await.unwrap();
+        assert_eq!(result[0].image_info, Some(expected));
+    }
+
     #[tokio::test]
     async fn test_fetch_unsafe_image() {
         let categories = vec![ModerationCategories::Drugs];
@@ -406,6 +1044,7 @@ mod tests {
             url: URL_UNSAFE_IMAGE.to_string(),
             force: false,
             response_type: ResponseType::Json,
+            ..Default::default()
         };
         let result = fetch(context, &Uuid::new_v4(), &params).await;
         assert!(result.is_ok());
@@ -427,6 +1066,7 @@ mod tests {
             url: URL_UNSAFE_IMAGE.to_string(),
             force: true,
             response_type: ResponseType::Json,
+            ..Default::default()
         };
         let result = fetch(context, &Uuid::new_v4(), &params).await;
         assert!(result.is_ok());
@@ -439,13 +1079,387 @@ mod tests {
         assert!(result.document.is_some());
     }
 
+    #[tokio::test]
+    async fn test_fetch_animation_blocked_frame() {
+        let mut bytes = Vec::new();
+        {
+            let mut encoder = GifEncoder::new(&mut bytes);
+            let frames = (0..6_u8).map(|i| {
+                let buffer = RgbaImage::from_pixel(40, 30, Rgba([i * 40, 0, 0, 255]));
+                Frame::from_parts(buffer, 0, 0, Delay::from_numer_denom_ms(100, 1))
+            });
+            encoder.encode_frames(frames).unwrap();
+        }
+        let doc = Document {
+            id: Uuid::new_v4(),
+            content_type: "image/gif".to_string(),
+            content_length: bytes.len() as u64,
+            bytes: Bytes::from(bytes),
+            url: URL_UNSAFE_IMAGE.to_string(),
+        };
+        // Only the last sampled frame is flagged
+        let (frames, _) = doc
+            .frame_documents(3, false, &ImageLimits::default())
+            .unwrap()
+            .unwrap();
+        let mut moderation_provider = DummyModerationProvider::new();
+        moderation_provider.set_document(
+            &frames.last().unwrap().bytes,
+            vec![ModerationCategories::Violence],
+        );
+        let mut context = construct_raw_context(Some(doc), None);
+        context.moderation_provider = Box::new(moderation_provider);
+        context.animation = Some(AnimationConfig {
+            max_frames: 3,
+            mode: AnimationMode::PerFrame,
+        });
+        let context = Arc::new(context);
+
+        let params = FetchRequestParams {
+            url: URL_UNSAFE_IMAGE.to_string(),
+            force: false,
+            response_type: ResponseType::Json,
+            ..Default::default()
+        };
+        let result = fetch(context, &Uuid::new_v4(), &params).await.unwrap();
+        assert_eq!(result.moderation_status, ModerationStatus::Blocked);
+        assert_eq!(result.categories, vec![ModerationCategories::Violence]);
+        assert!(result.document.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_image_hash_block() {
+        let doc = construct_image_document(URL_SAFE_IMAGE);
+        let hash = doc.perceptual_hash(&ImageLimits::default()).unwrap();
+        let mut context = construct_raw_context(Some(doc), None);
+        context.perceptual_hash = Some(PerceptualHashConfig {
+            max_distance: 4,
+            max_allow_distance: None,
+        });
+        let context = Arc::new(context);
+        context
+            .database
+            .add_image_hash(hash ^ 0b1, ImageHashAction::Block, &[])
+            .await
+            .unwrap();
+
+        let params = FetchRequestParams {
+            url: URL_SAFE_IMAGE.to_string(),
+            force: false,
+            response_type: ResponseType::Json,
+            ..Default::default()
+        };
+        let result = fetch(context.clone(), &Uuid::new_v4(), &params).await;
+        let result = result.unwrap();
+        assert_eq!(result.moderation_status, ModerationStatus::Blocked);
+        assert!(result.document.is_none());
+
+        let rows = context
+            .database
+            .get_moderation_result(&[URL_SAFE_IMAGE.to_string()])
+            .await
+            .unwrap();
+        assert_eq!(rows[0].provider, ModerationService::ImageHash);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_image_hash_allow() {
+        let categories = vec![ModerationCategories::Drugs];
+        let doc = construct_image_document(URL_UNSAFE_IMAGE);
+        let hash = doc.perceptual_hash(&ImageLimits::default()).unwrap();
+        let mut context = construct_raw_context(Some(doc), Some(categories));
+        context.perceptual_hash = Some(PerceptualHashConfig {
+            max_distance: 4,
+            max_allow_distance: None,
+        });
+        let context = Arc::new(context);
+        context
+            .database
+            .add_image_hash(hash, ImageHashAction::Allow, &[])
+            .await
+            .unwrap();
+
+        let params = FetchRequestParams {
+            url: URL_UNSAFE_IMAGE.to_string(),
+            force: false,
+            response_type: ResponseType::Json,
+            ..Default::default()
+        };
+        let result = fetch(context, &Uuid::new_v4(), &params).await;
+        let result = result.unwrap();
+        // The moderation provider would have blocked this image
+        assert_eq!(result.moderation_status, ModerationStatus::Allowed);
+        assert!(result.document.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_image_hash_precedence() {
+        let params = FetchRequestParams {
+            url: URL_UNSAFE_IMAGE.to_string(),
+            force: false,
+            response_type: ResponseType::Json,
+            ..Default::default()
+        };
+        let construct_context = |entries: &[(u64, ImageHashAction)]| {
+            let categories = vec![ModerationCategories::Drugs];
+            let doc = construct_image_document(URL_UNSAFE_IMAGE);
+            let hash = doc.perceptual_hash(&ImageLimits::default()).unwrap();
+            let mut context = construct_raw_context(Some(doc), Some(categories));
+            context.perceptual_hash = Some(PerceptualHashConfig {
+                max_distance: 4,
+                max_allow_distance: Some(1),
+            });
+            let entries: Vec<_> = entries
+                .iter()
+                .map(|(mask, action)| (hash ^ mask, action.clone()))
+                .collect();
+            async move {
+                for (hash, action) in entries {
+                    context
+                        .database
+                        .add_image_hash(hash, action, &[])
+                        .await
+                        .unwrap();
+                }
+                Arc::new(context)
+            }
+        };
+
+        // Allow entries beyond `max_allow_distance` are ignored, the
+        // moderation provider blocks the image
+        let context = construct_context(&[(0b11, ImageHashAction::Allow)]).await;
+        let result = fetch(context.clone(), &Uuid::new_v4(), &params).await;
+        assert_eq!(result.unwrap().moderation_status, ModerationStatus::Blocked);
+        let rows = context
+            .database
+            .get_moderation_result(&[URL_UNSAFE_IMAGE.to_string()])
+            .await
+            .unwrap();
+        assert_ne!(rows[0].provider, ModerationService::ImageHash);
+
+        // A Block entry wins over a closer Allow entry
+        let context =
+            construct_context(&[(0, ImageHashAction::Allow), (0b111, ImageHashAction::Block)])
+                .await;
+        let result = fetch(context.clone(), &Uuid::new_v4(), &params).await;
+        let result = result.unwrap();
+        assert_eq!(result.moderation_status, ModerationStatus::Blocked);
+        assert!(result.document.is_none());
+        let rows = context
+            .database
+            .get_moderation_result(&[URL_UNSAFE_IMAGE.to_string()])
+            .await
+            .unwrap();
+        assert_eq!(rows[0].provider, ModerationService::ImageHash);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_hash_list_match() {
+        let doc = construct_document(URL_SAFE_IMAGE);
+        let hash_list = format!("sha256,{}", sha256(&doc.bytes));
+        let mut context = construct_raw_context(Some(doc), None);
+        context.hash_list = Some(Arc::new(HashList::parse(&hash_list, 0).unwrap()));
+        let context = Arc::new(context);
+
+        // Never served, even when forced
+        let params = FetchRequestParams {
+            url: URL_SAFE_IMAGE.to_string(),
+            force: true,
+            response_type: ResponseType::Json,
+            ..Default::default()
+        };
+        let result = fetch(context.clone(), &Uuid::new_v4(), &params).await;
+        let result = result.unwrap();
+        assert_eq!(result.moderation_status, ModerationStatus::Blocked);
+        assert!(result.document.is_none());
+
+        let rows = context
+            .database
+            .get_moderation_result(&[URL_SAFE_IMAGE.to_string()])
+            .await
+            .unwrap();
+        assert!(rows[0].blocked);
+        assert_eq!(rows[0].provider, ModerationService::HashList);
+
+        // Also blocked once the verdict is in the database
+        let result = fetch(context, &Uuid::new_v4(), &params).await;
+        assert!(result.unwrap().document.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_strips_metadata() {
+        let mut cursor = Cursor::new(Vec::new());
+        DynamicImage::new_rgb8(8, 8)
+            .write_to(&mut cursor, ImageOutputFormat::Jpeg(90))
+            .unwrap();
+        let jpeg = cursor.into_inner();
+        let comment = b"\xFF\xFE\x00\x0Dserial 1234";
+        let bytes = [&jpeg[..2], &comment[..], &jpeg[2..]].concat();
+        let doc = Document {
+            id: Uuid::new_v4(),
+            content_type: "image/jpeg".to_string(),
+            content_length: bytes.len() as u64,
+            bytes: Bytes::from(bytes),
+            url: URL_SAFE_IMAGE.to_string(),
+        };
+        let mut context = construct_raw_context(Some(doc), None);
+        context.metadata = Some(MetadataConfig { strip: true });
+        let params = FetchRequestParams {
+            url: URL_SAFE_IMAGE.to_string(),
+            response_type: ResponseType::Raw,
+            ..Default::default()
+        };
+        let result = fetch(Arc::new(context), &Uuid::new_v4(), &params).await;
+        let document = result.unwrap().document.unwrap();
+        assert_eq!(document.bytes.len(), jpeg.len());
+        assert_eq!(document.content_length, jpeg.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_placeholder() {
+        let doc = construct_image_document(URL_UNSAFE_IMAGE);
+        let mut context = construct_raw_context(Some(doc), Some(vec![ModerationCategories::Drugs]));
+        context.placeholder = Some(PlaceholderConfig {
+            mode: PlaceholderMode::Pixelate,
+        });
+        let context = Arc::new(context);
+        let params = FetchRequestParams {
+            url: URL_UNSAFE_IMAGE.to_string(),
+            response_type: ResponseType::Raw,
+            ..Default::default()
+        };
+
+        // Both on first moderation and from the database verdict
+        for _ in 0..2 {
+            let result = fetch(context.clone(), &Uuid::new_v4(), &params)
+                .await
+                .unwrap();
+            assert_eq!(result.moderation_status, ModerationStatus::Blocked);
+            assert!(result.blurhash.is_none());
+            let document = result.document.unwrap();
+            let image = image::load_from_memory(&document.bytes).unwrap();
+            assert_eq!((image.width(), image.height()), (64, 64));
+        }
+
+        let doc = construct_image_document(URL_UNSAFE_IMAGE);
+        let mut context = construct_raw_context(Some(doc), Some(vec![ModerationCategories::Drugs]));
+        context.placeholder = Some(PlaceholderConfig {
+            mode: PlaceholderMode::BlurHash,
+        });
+        let result = fetch(Arc::new(context), &Uuid::new_v4(), &params)
+            .await
+            .unwrap();
+        assert!(result.document.is_none());
+        assert_eq!(result.blurhash.unwrap().len(), 28);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_svg_sanitized() {
+        let svg = r#"<svg xmlns="http://www.w3.org/2000/svg" width="10" height="10" onload="alert(1)"><script>alert(1)</script><rect width="10" height="10"/></svg>"#;
+        let doc = Document {
+            id: Uuid::new_v4(),
+            content_type: "image/svg+xml".to_string(),
+            content_length: svg.len() as u64,
+            bytes: Bytes::from(svg),
+            url: URL_SAFE_IMAGE.to_string(),
+        };
+        let context = construct_context(Some(doc), None);
+        let params = FetchRequestParams {
+            url: URL_SAFE_IMAGE.to_string(),
+            force: false,
+            response_type: ResponseType::Json,
+            ..Default::default()
+        };
+        let result = fetch(context, &Uuid::new_v4(), &params).await.unwrap();
+        assert_eq!(result.moderation_status, ModerationStatus::Allowed);
+        let document = result.document.unwrap();
+        assert_eq!(document.content_type, "image/svg+xml");
+        let served = String::from_utf8(document.bytes.to_vec()).unwrap();
+        assert!(served.contains("<rect"));
+        assert!(!served.contains("alert"));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_transform() {
+        let doc = construct_image_document(URL_SAFE_IMAGE);
+        let context = construct_context(Some(doc), None);
+        let params = FetchRequestParams {
+            url: URL_SAFE_IMAGE.to_string(),
+            force: false,
+            response_type: ResponseType::Raw,
+            width: Some(32),
+            format: Some(OutputFormat::Jpeg),
+            ..Default::default()
+        };
+        let result = fetch(context.clone(), &Uuid::new_v4(), &params).await;
+        let document = result.unwrap().document.unwrap();
+        assert_eq!(document.content_type, "image/jpeg");
+        let image = image::load_from_memory(&document.bytes).unwrap();
+        assert_eq!((image.width(), image.height()), (32, 32));
+
+        let params = FetchRequestParams {
+            url: URL_SAFE_IMAGE.to_string(),
+            format: Some(OutputFormat::Webp),
+            quality: Some(50),
+            ..Default::default()
+        };
+        let result = fetch(context.clone(), &Uuid::new_v4(), &params).await;
+        let document = result.unwrap().document.unwrap();
+        assert_eq!(document.content_type, "image/webp");
+
+        let params = FetchRequestParams {
+            url: URL_SAFE_IMAGE.to_string(),
+            width: Some(0),
+            ..Default::default()
+        };
+        let result = fetch(context.clone(), &Uuid::new_v4(), &params).await;
+        assert_eq!(result.err(), Some(Errors::InvalidTransform));
+
+        let params = FetchRequestParams {
+            url: URL_SAFE_IMAGE.to_string(),
+            quality: Some(101),
+            ..Default::default()
+        };
+        let result = fetch(context.clone(), &Uuid::new_v4(), &params).await;
+        assert_eq!(result.err(), Some(Errors::InvalidTransform));
+
+        // Within the width and height limits, but not the pixel limit
+        let params = FetchRequestParams {
+            url: URL_SAFE_IMAGE.to_string(),
+            width: Some(16384),
+            height: Some(16384),
+            fit: Some(FitMode::Fill),
+            ..Default::default()
+        };
+        let result = fetch(context.clone(), &Uuid::new_v4(), &params).await;
+        assert_eq!(result.err(), Some(Errors::InvalidTransform));
+
+        // Enlarging the 64x64 image more than allowed
+        let params = FetchRequestParams {
+            url: URL_SAFE_IMAGE.to_string(),
+            width: Some(1024),
+            height: Some(64),
+            fit: Some(FitMode::Cover),
+            ..Default::default()
+        };
+        let result = fetch(context, &Uuid::new_v4(), &params).await;
+        assert_eq!(result.err(), Some(Errors::InvalidTransform));
+    }
+
     #[tokio::test]
     async fn test_describe() {
         let context = construct_context(None, None);
         let database = &context.database;
         // Insert results into the database
         let result = database
-            .add_moderation_result(URL_SAFE_IMAGE, ModerationService::Aws, false, &[])
+            .add_moderation_result(
+                URL_SAFE_IMAGE,
+                ModerationService::Aws,
+                false,
+                &[],
+                &[],
+                None,
+            )
             .await;
         assert!(result.is_ok());
 
@@ -455,6 +1469,8 @@ mod tests {
                 ModerationService::Aws,
                 true,
                 &[ModerationCategories::Drugs],
+                &[],
+                None,
             )
             .await;
         assert!(result.is_ok());
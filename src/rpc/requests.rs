@@ -26,18 +26,72 @@ pub struct MethodHeader {
 }
 
 // Fetch method struct
-#[derive(Deserialize, PartialEq, Eq)]
+#[derive(Deserialize, PartialEq, Eq, Default)]
 pub enum ResponseType {
+    #[default]
     Json,
     Raw,
 }
 
+/// How an image is fitted to the requested width and height
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum FitMode {
+    /// Scaled to fit within the box, keeping the aspect ratio
+    #[default]
+    Contain,
+    /// Scaled and cropped to fill the box, keeping the aspect ratio
+    Cover,
+    /// Stretched to exactly fill the box
+    Fill,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Png,
+    Jpeg,
+    Webp,
+    /// Chosen from the formats listed in the `Accept` header of the request
+    Auto,
+}
+
+impl OutputFormat {
+    /// Picks the best format accepted by the client. Returns `None` if the
+    /// client only accepts the usual formats, so the source format is kept.
+    pub fn from_accept(accept: &str) -> Option<OutputFormat> {
+        accept.split(',').find_map(|entry| {
+            let mut parts = entry.split(';').map(|p| p.trim());
+            let media_type = parts.next()?;
+            let quality = parts
+                .find_map(|p| p.strip_prefix("q="))
+                .map_or(1_f32, |q| q.parse::<f32>().unwrap_or(0_f32));
+            (media_type.eq_ignore_ascii_case("image/webp") && quality > 0_f32)
+                .then_some(OutputFormat::Webp)
+        })
+    }
+}
+
 // Fetch method struct
-#[derive(Deserialize)]
+#[derive(Deserialize, Default)]
 pub struct FetchRequestParams {
     pub url: String,
     pub force: bool,
     pub response_type: ResponseType,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Only used when both width and height are given, defaults to `Contain`
+    pub fit: Option<FitMode>,
+    pub format: Option<OutputFormat>,
+    /// Encoding quality from 1 to 100 for JPEG and WebP output
+    pub quality: Option<u8>,
+}
+
+impl FetchRequestParams {
+    pub fn has_transform(&self) -> bool {
+        self.width.is_some()
+            || self.height.is_some()
+            || self.format.is_some_and(|f| f != OutputFormat::Auto)
+            || self.quality.is_some()
+    }
 }
 
 #[derive(Deserialize)]
@@ -65,3 +119,23 @@ pub struct ReportRequestParams {
 pub struct ReportRequest {
     pub params: ReportRequestParams,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_format_from_accept() {
+        assert_eq!(
+            OutputFormat::from_accept("image/avif,image/webp,image/apng,*/*;q=0.8"),
+            Some(OutputFormat::Webp)
+        );
+        assert_eq!(
+            OutputFormat::from_accept("image/png, IMAGE/WEBP; q=0.5"),
+            Some(OutputFormat::Webp)
+        );
+        assert_eq!(OutputFormat::from_accept("image/webp;q=0"), None);
+        assert_eq!(OutputFormat::from_accept("image/png,*/*"), None);
+        assert_eq!(OutputFormat::from_accept(""), None);
+    }
+}
@@ -11,13 +11,16 @@ use super::{
     requests::ResponseType,
 };
 use crate::{
-    document::Document,
+    document::{Document, ImageInfo},
     metrics,
     moderation::{ModerationCategories, ModerationService},
 };
 
 use super::VERSION;
 
+// Set on raw fetch responses, as placeholders of blocked images are served too
+pub const MODERATION_STATUS_HEADER: &str = "x-moderation-status";
+
 #[derive(Serialize)]
 pub enum RpcStatus {
     Ok,
@@ -53,6 +56,12 @@ pub struct ModerationResult {
     pub data: String,
     #[serde(skip_serializing)]
     pub document: Option<Arc<Document>>,
+    /// Placeholder for blocked images, when configured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blurhash: Option<String>,
+    /// Properties of the fetched image, when known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image_info: Option<ImageInfo>,
 }
 
 #[derive(Serialize)]
@@ -82,6 +91,8 @@ pub struct DescribeResult {
     pub status: DocumentStatus,
     pub categories: Vec<ModerationCategories>,
     pub provider: ModerationService,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image_info: Option<ImageInfo>,
 }
 
 #[derive(Serialize)]
@@ -130,6 +141,8 @@ impl FetchResponse {
         document: Option<Arc<Document>>,
         moderation_status: ModerationStatus,
         categories: Vec<ModerationCategories>,
+        blurhash: Option<String>,
+        image_info: Option<ImageInfo>,
         req_id: &Uuid,
     ) -> Response<Full<Bytes>> {
         match response_type {
@@ -142,6 +155,7 @@ impl FetchResponse {
                         .status(200)
                         .header(hyper::header::CONTENT_TYPE, doc.content_type.clone())
                         .header(hyper::header::CONTENT_LENGTH, doc.bytes.len())
+                        .header(MODERATION_STATUS_HEADER, format!("{:?}", moderation_status))
                         .body(Full::new(doc.bytes.clone()))
                         .unwrap_or_default()
                 } else {
@@ -150,6 +164,8 @@ impl FetchResponse {
                         None,
                         moderation_status,
                         categories,
+                        blurhash,
+                        image_info,
                         req_id,
                     )
                 }
@@ -163,6 +179,8 @@ impl FetchResponse {
                         categories,
                         data: document.map(|doc| doc.to_url()).unwrap_or_default(),
                         document: None,
+                        blurhash,
+                        image_info,
                     },
                 };
                 metrics::TRAFFIC
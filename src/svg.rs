@@ -0,0 +1,352 @@
+use std::io::Cursor;
+use std::sync::Arc;
+
+use image::io::Reader as ImageReader;
+use image::{Rgba, RgbaImage};
+use lazy_static::lazy_static;
+use log::warn;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::{Reader, Writer};
+use resvg::{tiny_skia, usvg};
+
+use crate::document::ImageLimits;
+use crate::metrics;
+
+type GenericError = Box<dyn std::error::Error + Send + Sync>;
+
+// Elements removed along with everything inside them
+const FORBIDDEN_ELEMENTS: [&str; 10] = [
+    "script",
+    "foreignobject",
+    "iframe",
+    "embed",
+    "object",
+    "audio",
+    "video",
+    "handler",
+    "listener",
+    "discard",
+];
+
+const ANIMATION_ELEMENTS: [&str; 5] = [
+    "animate",
+    "animatecolor",
+    "animatemotion",
+    "animatetransform",
+    "set",
+];
+
+// Embedded raster images are the only references allowed besides fragments
+const SAFE_DATA_PREFIXES: [&str; 4] = [
+    "data:image/png",
+    "data:image/jpeg",
+    "data:image/gif",
+    "data:image/webp",
+];
+
+lazy_static! {
+    static ref FONTS: Arc<usvg::fontdb::Database> = {
+        let mut fonts = usvg::fontdb::Database::new();
+        fonts.load_system_fonts();
+        Arc::new(fonts)
+    };
+}
+
+fn is_safe_reference(value: &str) -> bool {
+    let value = value.trim().to_ascii_lowercase();
+    value.starts_with('#') || SAFE_DATA_PREFIXES.iter().any(|p| value.starts_with(p))
+}
+
+/// Checks css or attribute values for anything that may load a resource
+fn has_external_reference(value: &str) -> bool {
+    let value = value.to_ascii_lowercase();
+    // Css escapes could hide any of the checks below
+    if value.contains("@import") || value.contains("javascript:") || value.contains('\\') {
+        return true;
+    }
+    value.match_indices("url(").any(|(index, _)| {
+        let target = value[index + 4..]
+            .trim_start()
+            .trim_start_matches(['"', '\'']);
+        !is_safe_reference(target)
+    })
+}
+
+fn is_safe_attribute(name: &str, value: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    if name.starts_with("on") {
+        false
+    } else if name == "href" || name.ends_with(":href") || name == "src" {
+        is_safe_reference(value)
+    } else {
+        !has_external_reference(value)
+    }
+}
+
+/// Animations may rewrite links or event handlers after sanitizing
+fn is_unsafe_animation(element: &BytesStart) -> bool {
+    element.attributes().flatten().any(|attr| {
+        if !attr
+            .key
+            .local_name()
+            .as_ref()
+            .eq_ignore_ascii_case(b"attributeName")
+        {
+            return false;
+        }
+        let target = String::from_utf8_lossy(&attr.value).to_ascii_lowercase();
+        target.starts_with("on") || target == "href" || target.ends_with(":href")
+    })
+}
+
+fn is_forbidden(element: &BytesStart) -> bool {
+    let name = String::from_utf8_lossy(element.local_name().as_ref()).to_ascii_lowercase();
+    FORBIDDEN_ELEMENTS.contains(&name.as_str())
+        || (ANIMATION_ELEMENTS.contains(&name.as_str()) && is_unsafe_animation(element))
+}
+
+/// Copies the element keeping only safe attributes
+fn sanitize_element(element: &BytesStart) -> BytesStart<'static> {
+    let name = String::from_utf8_lossy(element.name().as_ref()).into_owned();
+    let mut sanitized = BytesStart::new(name);
+    for attr in element.attributes().flatten() {
+        let key = String::from_utf8_lossy(attr.key.as_ref()).into_owned();
+        let safe = attr
+            .unescape_value()
+            .map(|value| is_safe_attribute(&key, &value))
+            .unwrap_or(false);
+        if safe {
+            sanitized.push_attribute(attr);
+        }
+    }
+    sanitized
+}
+
+/// Removes scripts, event handlers, external references and anything else
+/// that could execute or load content when the SVG is displayed. Doctypes
+/// are dropped as well, so entity expansion is not possible.
+pub fn sanitize(bytes: &[u8]) -> Result<Vec<u8>, GenericError> {
+    let mut reader = Reader::from_reader(bytes);
+    let mut writer = Writer::new(Vec::new());
+    let mut buf = Vec::new();
+    // Depth inside a removed element, zero when not removing
+    let mut skip_depth = 0_usize;
+    let mut in_style = false;
+    let mut seen_root = false;
+
+    loop {
+        let event = reader.read_event_into(&mut buf)?;
+        if skip_depth > 0 {
+            match event {
+                Event::Start(_) => skip_depth += 1,
+                Event::End(_) => skip_depth -= 1,
+                Event::Eof => break,
+                _ => {}
+            }
+            buf.clear();
+            continue;
+        }
+
+        match event {
+            Event::Start(ref element) | Event::Empty(ref element) if !seen_root => {
+                if !element.local_name().as_ref().eq_ignore_ascii_case(b"svg") {
+                    return Err("Document root is not an svg element".into());
+                }
+                seen_root = true;
+                let sanitized = sanitize_element(element);
+                match event {
+                    Event::Start(_) => writer.write_event(Event::Start(sanitized))?,
+                    _ => writer.write_event(Event::Empty(sanitized))?,
+                }
+            }
+            Event::Start(element) if is_forbidden(&element) => skip_depth = 1,
+            Event::Empty(element) if is_forbidden(&element) => {}
+            Event::Start(element) => {
+                in_style = element.local_name().as_ref().eq_ignore_ascii_case(b"style");
+                writer.write_event(Event::Start(sanitize_element(&element)))?;
+            }
+            Event::Empty(element) => {
+                writer.write_event(Event::Empty(sanitize_element(&element)))?;
+            }
+            Event::End(element) => {
+                in_style = false;
+                writer.write_event(Event::End(element))?;
+            }
+            Event::Text(text) => {
+                if !(in_style && has_external_reference(&text.unescape()?)) {
+                    writer.write_event(Event::Text(text))?;
+                }
+            }
+            Event::CData(data) => {
+                if !(in_style && has_external_reference(&String::from_utf8_lossy(&data))) {
+                    writer.write_event(Event::CData(data))?;
+                }
+            }
+            Event::Decl(decl) => writer.write_event(Event::Decl(decl))?,
+            Event::DocType(_) | Event::PI(_) | Event::Comment(_) => {}
+            Event::Eof => break,
+        }
+        buf.clear();
+    }
+
+    if !seen_root {
+        return Err("No svg element found".into());
+    }
+    Ok(writer.into_inner())
+}
+
+/// Whether the header of an embedded raster image is readable and within
+/// the limits. The renderer decodes embedded images without any limit.
+fn is_within_limits(data: &[u8], limits: &ImageLimits) -> bool {
+    let dimensions = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .ok()
+        .and_then(|reader| reader.into_dimensions().ok());
+    match dimensions {
+        Some((width, height)) => match limits.exceeded(width, height) {
+            Some(exceeded) => {
+                warn!(
+                    "Embedded svg image exceeds {} limit, skipping, width={}, height={}",
+                    exceeded, width, height
+                );
+                metrics::IMAGE_LIMITS.with_label_values(&[exceeded]).inc();
+                false
+            }
+            None => true,
+        },
+        None => {
+            warn!("Unable to read embedded svg image header, skipping");
+            false
+        }
+    }
+}
+
+/// Renders the SVG so that its larger side is `max_dimension` pixels.
+/// External images are never loaded, the SVG should be sanitized first.
+/// Embedded raster images exceeding the limits are left out.
+pub fn rasterize(
+    bytes: &[u8],
+    max_dimension: u32,
+    limits: &ImageLimits,
+) -> Result<RgbaImage, GenericError> {
+    let mut options = usvg::Options {
+        fontdb: FONTS.clone(),
+        ..usvg::Options::default()
+    };
+    let resolve_data = usvg::ImageHrefResolver::default_data_resolver();
+    options.image_href_resolver.resolve_data = Box::new(move |mime, data, options| {
+        let kind = resolve_data(mime, data, options)?;
+        match &kind {
+            usvg::ImageKind::JPEG(data)
+            | usvg::ImageKind::PNG(data)
+            | usvg::ImageKind::GIF(data)
+            | usvg::ImageKind::WEBP(data) => is_within_limits(data, limits).then_some(kind),
+            // Nested svgs are rendered without any images of their own
+            usvg::ImageKind::SVG(_) => Some(kind),
+        }
+    });
+    options.image_href_resolver.resolve_string = Box::new(|_, _| None);
+    let tree = usvg::Tree::from_data(bytes, &options)?;
+
+    let size = tree.size();
+    let scale = (max_dimension as f32 / size.width()).min(max_dimension as f32 / size.height());
+    let width = ((size.width() * scale).round() as u32).max(1);
+    let height = ((size.height() * scale).round() as u32).max(1);
+    let mut pixmap = tiny_skia::Pixmap::new(width, height).ok_or("Invalid svg dimensions")?;
+    resvg::render(
+        &tree,
+        tiny_skia::Transform::from_scale(scale, scale),
+        &mut pixmap.as_mut(),
+    );
+
+    let mut image = RgbaImage::new(width, height);
+    for (pixel, source) in image.pixels_mut().zip(pixmap.pixels()) {
+        let color = source.demultiply();
+        *pixel = Rgba([color.red(), color.green(), color.blue(), color.alpha()]);
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::prelude::*;
+
+    use super::*;
+
+    const SVG: &str = r##"<?xml version="1.0"?>
+<!DOCTYPE svg [<!ENTITY lol "lol">]>
+<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" width="20" height="10" onload="alert(1)">
+  <script>alert(1)</script>
+  <style>.shape { fill: url(#grad); }</style>
+  <style>@import url(http://evil.example/a.css);</style>
+  <foreignObject><div>hello</div></foreignObject>
+  <set attributeName="xlink:href" to="javascript:alert(1)"/>
+  <rect width="20" height="10" fill="red" onclick="alert(1)" style="fill: url(http://evil.example/x)"/>
+  <use xlink:href="#shape"/>
+  <image href="http://evil.example/tracker.png"/>
+  <image href="data:image/png;base64,AAAA"/>
+  <a href="javascript:alert(1)"><text>link</text></a>
+</svg>"##;
+
+    #[test]
+    fn test_sanitize() {
+        let sanitized = String::from_utf8(sanitize(SVG.as_bytes()).unwrap()).unwrap();
+        for removed in [
+            "DOCTYPE",
+            "script",
+            "alert",
+            "onload",
+            "onclick",
+            "evil.example",
+            "@import",
+            "foreignObject",
+            "<set",
+        ] {
+            assert!(!sanitized.contains(removed), "{} not removed", removed);
+        }
+        for kept in [
+            "fill=\"red\"",
+            "url(#grad)",
+            "xlink:href=\"#shape\"",
+            "data:image/png;base64,AAAA",
+            "<text>link</text>",
+        ] {
+            assert!(sanitized.contains(kept), "{} removed", kept);
+        }
+
+        assert!(sanitize(b"<html><svg/></html>").is_err());
+        assert!(sanitize(b"not xml").is_err());
+    }
+
+    #[test]
+    fn test_rasterize() {
+        let sanitized = sanitize(SVG.as_bytes()).unwrap();
+        let image = rasterize(&sanitized, 100, &ImageLimits::default()).unwrap();
+        assert_eq!(image.dimensions(), (100, 50));
+        assert_eq!(image.get_pixel(50, 25), &Rgba([255, 0, 0, 255]));
+    }
+
+    #[test]
+    fn test_rasterize_embedded_limits() {
+        let mut png = Cursor::new(Vec::new());
+        RgbaImage::from_pixel(64, 64, Rgba([0, 0, 255, 255]))
+            .write_to(&mut png, image::ImageOutputFormat::Png)
+            .unwrap();
+        let svg = format!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="10" height="10"><image width="10" height="10" href="data:image/png;base64,{}"/></svg>"#,
+            BASE64_STANDARD.encode(png.into_inner())
+        );
+        let sanitized = sanitize(svg.as_bytes()).unwrap();
+
+        let image = rasterize(&sanitized, 10, &ImageLimits::default()).unwrap();
+        assert_eq!(image.get_pixel(5, 5), &Rgba([0, 0, 255, 255]));
+
+        // Embedded images larger than the limits are never decoded
+        let limits = ImageLimits {
+            max_width: 32,
+            ..Default::default()
+        };
+        let image = rasterize(&sanitized, 10, &limits).unwrap();
+        assert_eq!(image.get_pixel(5, 5), &Rgba([0, 0, 0, 0]));
+    }
+}
@@ -1,3 +1,4 @@
+use md5::Md5;
 use sha2::{Digest, Sha256, Sha512};
 
 pub fn sha512(input: &[u8]) -> String {
@@ -12,6 +13,68 @@ pub fn sha256(input: &[u8]) -> String {
     format!("{:x}", hasher.finalize())
 }
 
+pub fn md5(input: &[u8]) -> String {
+    let mut hasher = Md5::new();
+    hasher.update(input);
+    format!("{:x}", hasher.finalize())
+}
+
+/// Number of differing bits between two hashes
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// Decodes `%XX` escapes, leaving malformed escapes as they are
+pub fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|hex| bytes[i] == b'%' && hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Removes `.` and `..` segments from a path as described in RFC 3986,
+/// section 5.2.4
+pub fn remove_dot_segments(path: &str) -> String {
+    let (prefix, relative) = match path.strip_prefix('/') {
+        Some(relative) => ("/", relative),
+        None => ("", path),
+    };
+    let mut segments: Vec<&str> = Vec::new();
+    let mut trailing_slash = false;
+    for segment in relative.split('/') {
+        trailing_slash = segment == "." || segment == "..";
+        match segment {
+            "." => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => segments.push(segment),
+        }
+    }
+    let mut result = format!("{}{}", prefix, segments.join("/"));
+    if trailing_slash && !segments.is_empty() {
+        result.push('/');
+    }
+    result
+}
+
 pub fn print_banner() {
     let banner = "
     ░█▀█░█▀▀░▀█▀░░░▀█▀░█▄█░█▀█░█▀▀░█▀▀░░░█▀█░█▀▄░█▀█░█░█░█░█